@@ -0,0 +1,33 @@
+//! The runtime side of the generated actor types.
+//!
+//! The [`lucidity::actor`] macro implements [`Actor`] for the type of the `impl` block it is put on, so that
+//! generic helpers (e.g., [`crate::sharding::Sharded`]) can spawn the actor, and wrap its process in a handle.
+
+use lunatic::{ap::ProcessRef, serializer::Bincode, AbstractProcess};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{dispatcher::Permit, limits::Lease, limits::Limits, CallOptions};
+
+/// A type that the [`lucidity::actor`] macro was put on.
+pub trait Actor: Sized {
+    /// The arguments of the actor's `#[init]` method (a tuple, when there are several of them).
+    type Args: Clone + Serialize + DeserializeOwned;
+
+    /// The process that owns the state of the actor.
+    type Process: AbstractProcess<Serializer = Bincode, Arg = (Permit, Option<Lease>, Self::Args)>;
+
+    /// The handle to a spawned actor.
+    type Ref: Copy + Serialize + DeserializeOwned;
+
+    /// Returns the name of the actor, unique within the application.
+    fn name() -> &'static str;
+
+    /// Returns the options of the actor's attribute.
+    fn options() -> CallOptions;
+
+    /// Returns the cluster-wide limits of the actor, if any.
+    fn limits() -> Option<Limits>;
+
+    /// Wraps the process of a spawned actor in a handle, using the timeouts of `options`.
+    fn handle(process: ProcessRef<Self::Process>, options: &CallOptions) -> Self::Ref;
+}
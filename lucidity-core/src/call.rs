@@ -0,0 +1,596 @@
+//! The runtime side of the generated job methods.
+//!
+//! The [`lucidity::job`] macro generates a call builder per job that owns a [`CallOptions`], and uses the
+//! functions in this module to place, start, and shutdown the job's service process.
+
+use core::time::Duration;
+use std::time::Instant;
+
+use lunatic::{
+    ap::{ProcessRef, StartupError},
+    host,
+    serializer::Bincode,
+    AbstractProcess, ProcessConfig,
+};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cluster,
+    dispatcher::{self, Permit},
+    limits::{self, Lease, Limits},
+    supervision::{Restart, RestartNode},
+    JobError,
+};
+
+/// The maximum amount of time that placement waits for the attributes of a new node, after it was first seen.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The scheme used to spread the calls of a fanout across nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fanout {
+    /// Calls are distributed across nodes in order.
+    #[default]
+    RoundRobin,
+    /// Each call is placed on a random node.
+    Random,
+}
+
+/// When the calls of a fanout are duplicated on another node, because they run much longer than their siblings.
+///
+/// Once `quorum` percent of the results of a fanout are in, every outstanding call that has been running longer than the `percentile`
+/// of the durations of the completed calls gets a duplicate on another node.  Each call is measured from its own start, rather than
+/// from the start of the fanout.  The first result wins, and the other call is cancelled.  This only makes sense for idempotent jobs.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Speculation {
+    /// The percentage of results that must be in before any call is duplicated.
+    pub quorum: f64,
+    /// The percentile of the durations of the completed calls beyond which a call is a straggler.
+    pub percentile: f64,
+}
+
+impl Default for Speculation {
+    fn default() -> Self {
+        Self { quorum: 75.0, percentile: 90.0 }
+    }
+}
+
+/// Tracks the calls of a fanout, and decides which of them to duplicate.
+#[derive(Clone, Debug)]
+pub struct Speculator {
+    speculation: Option<Speculation>,
+    started: Vec<Instant>,
+    durations: Vec<Duration>,
+    pending: Vec<bool>,
+    duplicated: Vec<bool>,
+}
+
+impl Speculator {
+    /// Creates a tracker for the calls of a fanout, where call `k` started at `started[k]`.
+    pub fn new(started: Vec<Instant>, speculation: Option<Speculation>) -> Self {
+        let count = started.len();
+
+        Self {
+            speculation,
+            started,
+            durations: Vec::new(),
+            pending: vec![true; count],
+            duplicated: vec![false; count],
+        }
+    }
+
+    /// Records the result of call `k`.
+    pub fn finished(&mut self, k: usize) {
+        if self.pending[k] {
+            self.pending[k] = false;
+            self.durations.push(self.started[k].elapsed());
+        }
+    }
+
+    /// Returns the outstanding calls that should be duplicated now, and marks them as duplicated.
+    pub fn stragglers(&mut self) -> Vec<usize> {
+        let Some(speculation) = self.speculation else {
+            return Vec::new();
+        };
+
+        let count = self.pending.len();
+        let quorum = ((speculation.quorum / 100.0) * count as f64).ceil().max(1.0) as usize;
+        if self.durations.len() < quorum || self.durations.len() == count {
+            return Vec::new();
+        }
+
+        // Use the nearest rank, so that the threshold is always one of the observed durations.
+        let mut durations = self.durations.clone();
+        durations.sort();
+        let rank = ((speculation.percentile / 100.0) * durations.len() as f64).ceil().max(1.0) as usize;
+        let threshold = durations[rank.min(durations.len()) - 1];
+
+        let stragglers = (0..count)
+            .filter(|k| self.pending[*k] && !self.duplicated[*k] && self.started[*k].elapsed() > threshold)
+            .collect::<Vec<_>>();
+        for k in &stragglers {
+            self.duplicated[*k] = true;
+        }
+
+        stragglers
+    }
+}
+
+/// The options of a single call to a job.
+///
+/// The defaults of every job are the values given to its `lucidity::job` attribute, and they can be
+/// overridden per call with the generated call builder.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CallOptions {
+    /// The maximum memory of the process running the job.
+    pub memory: u64,
+    /// The maximum fuel of the process running the job.
+    pub fuel: u64,
+    /// The priority of the job on a saturated node.
+    pub priority: u32,
+    /// The scheme used to place the calls of a fanout.
+    pub fanout: Fanout,
+    /// The node to run on, rather than a node picked by the placement scheme.
+    pub node: Option<u64>,
+    /// The amount of time after which the call fails with [`JobError::Timeout`].
+    pub timeout: Option<Duration>,
+    /// The labels that a node must have to run the job (e.g., `("region", "ord")`).
+    pub requires: Vec<(String, String)>,
+    /// When the calls of a fanout are duplicated (only ever set for idempotent jobs).
+    pub speculation: Option<Speculation>,
+    /// When the worker of the job is restarted after it dies.
+    pub restart: Restart,
+    /// Where the worker of the job is restarted.
+    pub restart_node: RestartNode,
+    /// The maximum number of restarts within `restart_period`.
+    pub max_restarts: u32,
+    /// The period over which restarts are counted.
+    pub restart_period: Duration,
+    /// The retry interval when starting the service process.
+    pub init_retry_interval: Duration,
+    /// The retry interval when waiting on a blocking result.
+    pub sync_retry_interval: Duration,
+    /// The retry interval when starting the job asynchronously.
+    pub async_init_retry_interval: Duration,
+    /// The retry interval when polling a non-blocking result.
+    pub async_get_retry_interval: Duration,
+    /// The retry interval when the job sets its result on the service process.
+    pub async_set_retry_interval: Duration,
+    /// The retry interval when shutting the service process down.
+    pub shutdown_retry_interval: Duration,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            memory: 100 * 1024 * 1024,
+            fuel: 10,
+            priority: dispatcher::DEFAULT_PRIORITY,
+            fanout: Fanout::default(),
+            node: None,
+            timeout: None,
+            requires: Vec::new(),
+            speculation: None,
+            restart: Restart::default(),
+            restart_node: RestartNode::default(),
+            max_restarts: 3,
+            restart_period: Duration::from_secs(5),
+            init_retry_interval: Duration::from_millis(100),
+            sync_retry_interval: Duration::from_millis(100),
+            async_init_retry_interval: Duration::from_millis(100),
+            async_get_retry_interval: Duration::from_millis(100),
+            async_set_retry_interval: Duration::from_millis(100),
+            shutdown_retry_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl CallOptions {
+    /// Returns the point in time at which a call started now times out, if it has a timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|t| Instant::now() + t)
+    }
+
+    /// Returns the process configuration for the job's processes.
+    pub fn process_config(&self) -> ProcessConfig {
+        let mut config = ProcessConfig::new().unwrap();
+        config.set_can_spawn_processes(true);
+        config.set_can_create_configs(true);
+        config.set_can_compile_modules(true);
+        config.set_max_fuel(self.fuel);
+        config.set_max_memory(self.memory);
+
+        config
+    }
+
+    /// Returns the local node, if it satisfies the placement requirements of the call.
+    pub fn local_node(&self) -> Result<u64, JobError> {
+        let node = host::node_id();
+        if !self.requires.is_empty() && !cluster::NodeInfo::local().satisfies(&self.requires) {
+            return Err(JobError::NoEligibleNode);
+        }
+
+        Ok(node)
+    }
+
+    /// Returns the node for a single remote call.
+    pub fn remote_node(&self) -> Result<u64, JobError> {
+        if let Some(node) = self.node {
+            return self.pinned(node);
+        }
+
+        let nodes = self.wait_for_eligible_nodes();
+        nodes.choose(&mut rand::thread_rng()).copied().ok_or_else(|| self.unplaced())
+    }
+
+    /// Returns a node other than `node` for a duplicate call, if there is one (and the call is not pinned to a node).
+    pub fn other_node(&self, node: u64) -> u64 {
+        if self.node.is_some() {
+            return node;
+        }
+
+        let nodes = self.eligible_nodes().into_iter().filter(|n| *n != node).collect::<Vec<_>>();
+        nodes.choose(&mut rand::thread_rng()).copied().unwrap_or(node)
+    }
+
+    /// Returns an eligible node that is not in `tried`, if there is one, e.g., to place a call again after the nodes in `tried`
+    /// turned out to be unavailable.
+    pub fn untried_node(&self, tried: &[u64]) -> Option<u64> {
+        let nodes = self.eligible_nodes().into_iter().filter(|n| !tried.contains(n)).collect::<Vec<_>>();
+        nodes.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// Returns the nodes for the `count` calls of a fanout.
+    pub fn fanout_nodes(&self, count: usize) -> Result<Vec<u64>, JobError> {
+        if let Some(node) = self.node {
+            return Ok(vec![self.pinned(node)?; count]);
+        }
+
+        let nodes = self.wait_for_eligible_nodes();
+        if nodes.is_empty() {
+            return Err(self.unplaced());
+        }
+        let random = &mut rand::thread_rng();
+
+        Ok((0..count)
+            .map(|k| match self.fanout {
+                Fanout::RoundRobin => nodes[k % nodes.len()],
+                Fanout::Random => *nodes.choose(random).unwrap(),
+            })
+            .collect())
+    }
+
+    /// Returns the nodes for a broadcast: every remote node that satisfies the placement requirements of the call.
+    ///
+    /// Unlike the other placements, quarantined nodes are included, so that their failures are reported.
+    pub fn broadcast_nodes(&self) -> Vec<u64> {
+        let nodes = lunatic::distributed::nodes();
+        if self.requires.is_empty() {
+            return nodes;
+        }
+
+        let cluster = cluster::Cluster::get();
+        cluster.wait_for_attributes(&nodes, METADATA_TIMEOUT);
+
+        cluster.nodes().into_iter().filter(|n| nodes.contains(&n.id) && n.satisfies(&self.requires)).map(|n| n.id).collect()
+    }
+
+    /// Returns the healthy remote nodes that satisfy the placement requirements of the call.
+    pub fn eligible_nodes(&self) -> Vec<u64> {
+        eligible_nodes(&self.requires)
+    }
+
+    /// Returns the eligible nodes, waiting for one until the timeout of the call (if any) passes, e.g., while stopped nodes start again.
+    ///
+    /// Every placement that finds no eligible node is reported to the [`cluster::Cluster`].
+    fn wait_for_eligible_nodes(&self) -> Vec<u64> {
+        let deadline = self.deadline();
+        let mut reported = false;
+
+        loop {
+            let nodes = self.eligible_nodes();
+            if !nodes.is_empty() {
+                return nodes;
+            }
+
+            if !reported {
+                cluster::report_unplaced();
+                reported = true;
+            }
+
+            if self.timeout.is_none() || expired(deadline) {
+                return nodes;
+            }
+
+            lunatic::sleep(self.init_retry_interval);
+        }
+    }
+
+    /// Returns the error of a placement that found no eligible node: [`JobError::NodeUnavailable`] if a node that satisfies the
+    /// placement requirements is only left out because it is quarantined, and [`JobError::NoEligibleNode`] otherwise.
+    fn unplaced(&self) -> JobError {
+        let nodes = lunatic::distributed::nodes();
+        let quarantined = cluster::health().into_iter().filter(|h| h.quarantined && nodes.contains(&h.node)).map(|h| h.node).collect::<Vec<_>>();
+
+        let unavailable = if self.requires.is_empty() {
+            quarantined.first().copied()
+        } else {
+            cluster::Cluster::get()
+                .nodes()
+                .into_iter()
+                .find(|n| quarantined.contains(&n.id) && n.satisfies(&self.requires))
+                .map(|n| n.id)
+        };
+
+        unavailable.map_or(JobError::NoEligibleNode, JobError::NodeUnavailable)
+    }
+
+    fn pinned(&self, node: u64) -> Result<u64, JobError> {
+        if !cluster::is_connected(node) {
+            return Err(JobError::UnknownNode(node));
+        }
+
+        if !self.requires.is_empty() {
+            // The eligible nodes are remote, so the local node is checked against its own attributes.
+            let eligible = if node == host::node_id() {
+                cluster::NodeInfo::local().satisfies(&self.requires)
+            } else {
+                eligible_nodes(&self.requires).contains(&node)
+            };
+            if !eligible {
+                return Err(JobError::NoEligibleNode);
+            }
+        }
+
+        Ok(node)
+    }
+}
+
+/// Parses placement requirements of the form `key=value,key=value` (e.g., `region=ord,size=large`).
+///
+/// Entries without a `=` are ignored.
+pub fn parse_requirements(requirements: &str) -> Vec<(String, String)> {
+    requirements
+        .split(',')
+        .filter_map(|r| r.split_once('='))
+        .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+/// Returns the healthy remote nodes whose attributes satisfy `requires`.
+pub fn eligible_nodes(requires: &[(String, String)]) -> Vec<u64> {
+    let healthy = cluster::healthy_nodes();
+    if requires.is_empty() {
+        return healthy;
+    }
+
+    // Give the membership process a chance to learn the attributes of the nodes it has not probed yet (once per node).
+    let cluster = cluster::Cluster::get();
+    cluster.wait_for_attributes(&healthy, METADATA_TIMEOUT);
+
+    cluster.nodes().into_iter().filter(|n| healthy.contains(&n.id) && n.satisfies(requires)).map(|n| n.id).collect()
+}
+
+/// Returns whether `deadline` has passed.
+pub fn expired(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |d| Instant::now() >= d)
+}
+
+/// Runs `start` on `node`, and, unless the call is `pinned` (or its options pin it to a node), runs it again on another eligible
+/// node whenever the node it ran on turns out to be unavailable, or gone (i.e., `start` returns [`JobError::NodeUnavailable`], or
+/// [`JobError::UnknownNode`]).
+///
+/// Each node is tried at most once, so once no untried eligible node is left, the error of the last node is returned.
+pub fn place<R>(node: u64, options: &CallOptions, pinned: bool, deadline: Option<Instant>, mut start: impl FnMut(u64) -> Result<R, JobError>) -> Result<R, JobError> {
+    let mut tried = Vec::new();
+    let mut node = node;
+
+    loop {
+        if expired(deadline) {
+            return Err(JobError::Timeout);
+        }
+
+        match start(node) {
+            Err(e @ (JobError::NodeUnavailable(_) | JobError::UnknownNode(_))) if !pinned && options.node.is_none() => {
+                tried.push(node);
+                node = options.untried_node(&tried).ok_or(e)?;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Starts the service process `S` for one call of `job` on `node`.
+///
+/// The cluster-wide `limits` of the job (if any) and the dispatcher of `node` are waited on first.  Timed out
+/// starts are reported to the health monitor, and once `node` is quarantined (or leaves the cluster), [`JobError::NodeUnavailable`]
+/// (or [`JobError::UnknownNode`]) is returned, so that the caller can place the call elsewhere.
+pub fn start<S>(node: u64, job: &str, limits: Option<Limits>, options: &CallOptions, deadline: Option<Instant>) -> Result<ProcessRef<S>, JobError>
+where
+    S: AbstractProcess<Serializer = Bincode, Arg = (Permit, Option<Lease>, CallOptions)>,
+{
+    start_with::<S>(node, job, limits, options, deadline, |permit, lease| (permit, lease, options.clone()))
+}
+
+/// Like [`start`], but the argument of the service process is built by `arg` from the permit and the lease (e.g., for the
+/// processes of the [`lucidity::actor`] macro, which take their state's arguments on startup).
+///
+/// The permit and the lease are released again if the service process does not start.
+pub fn start_with<S>(node: u64, job: &str, limits: Option<Limits>, options: &CallOptions, deadline: Option<Instant>, arg: impl Fn(Permit, Option<Lease>) -> S::Arg) -> Result<ProcessRef<S>, JobError>
+where
+    S: AbstractProcess<Serializer = Bincode>,
+{
+    // Wait for the cluster-wide limits first, so that no node capacity is held while waiting.
+    let lease = match limits {
+        Some(limits) => Some(limits::acquire(job, limits, options.init_retry_interval, deadline)?),
+        None => None,
+    };
+    let permit = match dispatcher::acquire(node, options.priority, options.init_retry_interval, deadline) {
+        Ok(permit) => permit,
+        Err(e) => {
+            if let Some(lease) = lease {
+                lease.release();
+            }
+            return Err(e);
+        }
+    };
+
+    let result = start_service::<S>(node, options, deadline, || arg(permit.clone(), lease.clone()));
+    if result.is_err() {
+        permit.release();
+        if let Some(lease) = lease {
+            lease.release();
+        }
+    }
+
+    result
+}
+
+fn start_service<S>(node: u64, options: &CallOptions, deadline: Option<Instant>, arg: impl Fn() -> S::Arg) -> Result<ProcessRef<S>, JobError>
+where
+    S: AbstractProcess<Serializer = Bincode>,
+{
+    let config = options.process_config();
+
+    loop {
+        if expired(deadline) {
+            return Err(JobError::Timeout);
+        }
+
+        // Spawning "remotely" on the local node is not supported by every runtime, so local starts spawn directly.
+        let builder = if node == host::node_id() { S::configure(&config) } else { S::on_node(node).configure(&config) };
+
+        match builder.start_timeout(arg(), options.init_retry_interval) {
+            Ok(service) => return Ok(service),
+            Err(StartupError::TimedOut) => {
+                if !cluster::is_connected(node) {
+                    return Err(JobError::UnknownNode(node));
+                }
+
+                cluster::report_failure(node);
+                if !cluster::is_healthy(node) {
+                    return Err(JobError::NodeUnavailable(node));
+                }
+            }
+            Err(e) => return Err(JobError::Startup(format!("{:?}", e))),
+        }
+    }
+}
+
+/// Shuts the service process down, retrying every `shutdown_retry_interval`.
+pub fn shutdown<S>(service: ProcessRef<S>, options: &CallOptions)
+where
+    S: AbstractProcess<Serializer = Bincode>,
+{
+    loop {
+        if service.with_timeout(options.shutdown_retry_interval).shutdown().is_ok() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The tests that start processes run in `lunatic` (see the readme), so they are only built for `wasm32`.
+
+    use super::*;
+
+    #[test]
+    fn stragglers_are_measured_from_their_own_start() {
+        let now = Instant::now();
+        let started = vec![
+            now - Duration::from_secs(10),
+            now - Duration::from_secs(10),
+            now - Duration::from_secs(10),
+            now - Duration::from_secs(20),
+            now,
+        ];
+        let mut speculator = Speculator::new(started, Some(Speculation { quorum: 50.0, percentile: 90.0 }));
+
+        for k in 0..3 {
+            speculator.finished(k);
+        }
+
+        // The call that started recently is not a straggler, however long ago the fanout started.
+        assert_eq!(speculator.stragglers(), vec![3]);
+        assert!(speculator.stragglers().is_empty());
+    }
+
+    #[test]
+    fn stragglers_wait_for_the_quorum() {
+        let started = vec![Instant::now() - Duration::from_secs(10); 4];
+        let mut speculator = Speculator::new(started, Some(Speculation { quorum: 75.0, percentile: 90.0 }));
+
+        speculator.finished(0);
+        speculator.finished(1);
+
+        assert!(speculator.stragglers().is_empty());
+    }
+
+    /// A service process that never starts.
+    #[cfg(target_arch = "wasm32")]
+    struct Failing;
+
+    #[cfg(target_arch = "wasm32")]
+    #[lunatic::abstract_process(serializer = lunatic::serializer::Bincode)]
+    impl Failing {
+        #[init]
+        fn init(_: lunatic::ap::Config<Self>, _: (Permit, Option<Lease>, CallOptions)) -> Result<Self, ()> {
+            Err(())
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[lunatic::test]
+    fn failed_start_releases_the_permit() {
+        let node = host::node_id();
+        let result = start::<Failing>(node, "failing", None, &CallOptions::default(), None);
+
+        assert!(matches!(result, Err(JobError::Startup(_))));
+        assert_eq!(dispatcher::stats(node).unwrap().running, 0);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[lunatic::test]
+    fn placement_stops_once_every_node_was_tried() {
+        let node = host::node_id();
+        let mut attempts = 0;
+        let result = place::<()>(node, &CallOptions::default(), false, None, |n| {
+            attempts += 1;
+            Err(JobError::NodeUnavailable(n))
+        });
+
+        assert_eq!(result, Err(JobError::NodeUnavailable(node)));
+        assert_eq!(attempts, 1);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[lunatic::test]
+    fn placement_fails_when_the_only_node_left() {
+        let mut attempts = 0;
+        let result = place::<()>(u64::MAX, &CallOptions::default(), false, None, |n| {
+            attempts += 1;
+            Err(JobError::UnknownNode(n))
+        });
+
+        assert_eq!(result, Err(JobError::UnknownNode(u64::MAX)));
+        assert_eq!(attempts, 1);
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[lunatic::test]
+    fn calls_pinned_to_the_local_node_check_its_labels() {
+        std::env::set_var("LUCIDITY_LABELS", "role=test");
+        let node = host::node_id();
+        let mut options = CallOptions {
+            node: Some(node),
+            requires: parse_requirements("role=test"),
+            ..CallOptions::default()
+        };
+
+        assert_eq!(options.remote_node(), Ok(node));
+
+        options.requires = parse_requirements("role=other");
+        assert_eq!(options.remote_node(), Err(JobError::NoEligibleNode));
+    }
+}
@@ -0,0 +1,394 @@
+//! Typed channels between processes, wherever they run.
+//!
+//! A channel is a small process, spawned on the node that creates the channel, which buffers up to `capacity` values.
+//! Senders block while the buffer is full (backpressure), and the receiver blocks while it is empty.  Both ends can be
+//! serialized (e.g., into the arguments of a job), and used from any node.
+//!
+//! The channel watches the processes that use its ends.  The receiving end belongs to the process that created the channel,
+//! until another process receives from it.  The sending end belongs to every process that it was passed to (i.e., that deserialized
+//! it, e.g., from the arguments of a job), that sent on it, or that called [`Sender::attach`].  Once the receiving process exits,
+//! sends fail with [`ChannelError::Closed`], and once every sending process exited, receives fail with [`ChannelError::Closed`] as
+//! soon as the buffer is drained.  The channel process exits once both sides are gone.
+
+use core::time::Duration;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{self, Display, Formatter},
+};
+
+use lunatic::{host, serializer::Bincode, Mailbox, Process, ProcessConfig, Tag};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+use crate::watch;
+
+/// The capacity of the channels created with [`channel`].
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// The amount of time that a cancelled operation waits for the channel to confirm the cancellation.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The error returned by the operations on a channel.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelError {
+    /// The other side of the channel is gone (and, for receives, the buffer is drained).
+    Closed,
+    /// The operation did not complete before its timeout.
+    Timeout,
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Closed => write!(f, "The channel is closed."),
+            ChannelError::Timeout => write!(f, "The channel operation timed out."),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+/// A process that uses one of the ends of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Peer {
+    node: u64,
+    process: u64,
+}
+
+impl Peer {
+    fn current() -> Self {
+        Self {
+            node: host::node_id(),
+            process: host::process_id(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+enum Command<T> {
+    Send { value: T, from: Peer, reply: ReplyTo<T>, tag: Tag },
+    Recv { from: Peer, reply: ReplyTo<T>, tag: Tag },
+    Cancel { from: Peer, tag: Tag },
+    Attach(Peer),
+    Exited(Peer),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+enum Reply<T> {
+    Sent,
+    Received(T),
+    Closed,
+    Cancelled,
+}
+
+/// Notifies a channel once one of its peers exits.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+struct PeerExited<T> {
+    channel: Process<Command<T>, Bincode>,
+    peer: Peer,
+}
+
+impl<T> watch::OnExit for PeerExited<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn on_exit(self) {
+        self.channel.send(Command::Exited(self.peer));
+    }
+}
+
+/// Creates a channel with a buffer of [`DEFAULT_CAPACITY`] values.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>)
+where
+    T: Serialize + DeserializeOwned,
+{
+    bounded(DEFAULT_CAPACITY)
+}
+
+/// Creates a channel with a buffer of `capacity` values (at least one).
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>)
+where
+    T: Serialize + DeserializeOwned,
+{
+    // The channel may outlive the process that creates it, so it must not inherit its fuel or memory limits.
+    let mut config = ProcessConfig::new().unwrap();
+    config.set_can_spawn_processes(true);
+
+    let channel = Process::<Command<T>, Bincode>::spawn_config(&config, (capacity.max(1), Peer::current()), run::<T>);
+
+    (Sender { channel }, Receiver { channel })
+}
+
+/// The sending end of a channel.
+#[derive(Debug, Serialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+pub struct Sender<T> {
+    channel: Process<Command<T>, Bincode>,
+}
+
+// A sender is deserialized by the process that it was passed to, which becomes a sender of the channel right away, so that the
+// receiver learns when that process exits, even if it never sends.
+impl<'de, T> Deserialize<'de> for Sender<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename = "Sender", bound = "T: Serialize + DeserializeOwned")]
+        struct Raw<T> {
+            channel: Process<Command<T>, Bincode>,
+        }
+
+        let sender = Self {
+            channel: Raw::deserialize(deserializer)?.channel,
+        };
+        sender.attach();
+
+        Ok(sender)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self { channel: self.channel }
+    }
+}
+
+impl<T> Sender<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Sends `value`, blocking while the buffer of the channel is full.
+    ///
+    /// A process that sends for the first time after both sides of the channel are gone is never answered, so prefer
+    /// [`Sender::send_timeout`] when the receiver may have exited long ago.
+    pub fn send(&self, value: T) -> Result<(), ChannelError> {
+        self.send_inner(value, None)
+    }
+
+    /// Sends `value`, blocking while the buffer of the channel is full, for up to `timeout`.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), ChannelError> {
+        self.send_inner(value, Some(timeout))
+    }
+
+    /// Registers the calling process as a sender, so that the channel stays open for the receiver until the process exits, even
+    /// before the process sent anything.  Processes that the sender was passed to are registered when they deserialize it.
+    pub fn attach(&self) {
+        self.channel.send(Command::Attach(Peer::current()));
+    }
+
+    fn send_inner(&self, value: T, timeout: Option<Duration>) -> Result<(), ChannelError> {
+        match call(self.channel, timeout, |from, reply, tag| Command::Send { value, from, reply, tag }) {
+            Some(Reply::Sent) => Ok(()),
+            Some(Reply::Cancelled) => Err(ChannelError::Timeout),
+            _ => Err(ChannelError::Closed),
+        }
+    }
+}
+
+/// The receiving end of a channel.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+pub struct Receiver<T> {
+    channel: Process<Command<T>, Bincode>,
+}
+
+impl<T> Receiver<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Receives the next value, blocking while the buffer of the channel is empty.
+    pub fn recv(&self) -> Result<T, ChannelError> {
+        self.recv_inner(None)
+    }
+
+    /// Receives the next value, blocking while the buffer of the channel is empty, for up to `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, ChannelError> {
+        self.recv_inner(Some(timeout))
+    }
+
+    /// Returns an iterator over the values of the channel, which ends once the channel is closed.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv().ok())
+    }
+
+    fn recv_inner(&self, timeout: Option<Duration>) -> Result<T, ChannelError> {
+        match call(self.channel, timeout, |from, reply, tag| Command::Recv { from, reply, tag }) {
+            Some(Reply::Received(value)) => Ok(value),
+            Some(Reply::Cancelled) => Err(ChannelError::Timeout),
+            _ => Err(ChannelError::Closed),
+        }
+    }
+}
+
+/// Sends a command to the channel, and waits for its reply.
+///
+/// A command that times out is cancelled, and the channel answers the cancellation instead, unless it answered the command
+/// in the meantime, so that no value is lost.  `None` is returned if the channel does not answer at all (i.e., it exited).
+fn call<T>(channel: Process<Command<T>, Bincode>, timeout: Option<Duration>, command: impl FnOnce(Peer, ReplyTo<T>, Tag) -> Command<T>) -> Option<Reply<T>>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let from = Peer::current();
+    let tag = Tag::new();
+    let this = unsafe { Process::<Reply<T>, Bincode>::this() };
+    let mailbox = unsafe { Mailbox::<Reply<T>, Bincode>::new() };
+
+    channel.send(command(from, this, tag));
+
+    let Some(timeout) = timeout else {
+        return Some(mailbox.tag_receive(&[tag]));
+    };
+
+    if let Ok(reply) = mailbox.tag_receive_timeout(&[tag], timeout) {
+        return Some(reply);
+    }
+
+    channel.send(Command::Cancel { from, tag });
+    mailbox.tag_receive_timeout(&[tag], CANCEL_TIMEOUT).ok()
+}
+
+/// The process to answer a command to.
+type ReplyTo<T> = Process<Reply<T>, Bincode>;
+
+struct State<T> {
+    this: Process<Command<T>, Bincode>,
+    capacity: usize,
+    buffer: VecDeque<T>,
+    blocked: VecDeque<(Peer, Tag, T, ReplyTo<T>)>,
+    waiting: VecDeque<(Peer, Tag, ReplyTo<T>)>,
+    receiver: Option<Peer>,
+    senders: HashSet<Peer>,
+    had_senders: bool,
+    watched: HashSet<Peer>,
+}
+
+impl<T> State<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn watch(&mut self, peer: Peer) {
+        if self.watched.insert(peer) {
+            watch::on_exit(peer.node, peer.process, PeerExited { channel: self.this, peer });
+        }
+    }
+
+    fn add_sender(&mut self, peer: Peer) {
+        if self.senders.insert(peer) {
+            self.had_senders = true;
+            self.watch(peer);
+        }
+    }
+
+    /// Returns whether every sending process exited.
+    fn senders_gone(&self) -> bool {
+        self.had_senders && self.senders.is_empty()
+    }
+
+    fn send(&mut self, value: T, from: Peer, reply: ReplyTo<T>, tag: Tag) {
+        self.add_sender(from);
+
+        if self.receiver.is_none() {
+            reply.tag_send(tag, Reply::Closed);
+        } else if let Some((_, waiter_tag, waiter)) = self.waiting.pop_front() {
+            waiter.tag_send(waiter_tag, Reply::Received(value));
+            reply.tag_send(tag, Reply::Sent);
+        } else if self.buffer.len() < self.capacity {
+            self.buffer.push_back(value);
+            reply.tag_send(tag, Reply::Sent);
+        } else {
+            self.blocked.push_back((from, tag, value, reply));
+        }
+    }
+
+    fn recv(&mut self, from: Peer, reply: ReplyTo<T>, tag: Tag) {
+        // Receiving from another process moves the receiving end to it.
+        if self.receiver != Some(from) {
+            self.receiver = Some(from);
+            self.watch(from);
+        }
+
+        if let Some(value) = self.buffer.pop_front() {
+            reply.tag_send(tag, Reply::Received(value));
+
+            // Make room for the first blocked sender.
+            if let Some((_, sender_tag, value, sender)) = self.blocked.pop_front() {
+                self.buffer.push_back(value);
+                sender.tag_send(sender_tag, Reply::Sent);
+            }
+        } else if self.senders_gone() {
+            reply.tag_send(tag, Reply::Closed);
+        } else {
+            self.waiting.push_back((from, tag, reply));
+        }
+    }
+
+    fn cancel(&mut self, from: Peer, tag: Tag) {
+        if let Some(k) = self.waiting.iter().position(|(peer, t, _)| *peer == from && *t == tag) {
+            let (_, tag, reply) = self.waiting.remove(k).unwrap();
+            reply.tag_send(tag, Reply::Cancelled);
+        } else if let Some(k) = self.blocked.iter().position(|(peer, t, _, _)| *peer == from && *t == tag) {
+            let (_, tag, _, reply) = self.blocked.remove(k).unwrap();
+            reply.tag_send(tag, Reply::Cancelled);
+        }
+    }
+
+    fn exited(&mut self, peer: Peer) {
+        self.senders.remove(&peer);
+        self.waiting.retain(|(p, _, _)| *p != peer);
+        self.blocked.retain(|(p, _, _, _)| *p != peer);
+
+        if self.receiver == Some(peer) {
+            self.receiver = None;
+            self.buffer.clear();
+
+            for (_, tag, _, reply) in self.blocked.drain(..) {
+                reply.tag_send(tag, Reply::Closed);
+            }
+        }
+
+        if self.senders_gone() {
+            for (_, tag, reply) in self.waiting.drain(..) {
+                reply.tag_send(tag, Reply::Closed);
+            }
+        }
+    }
+}
+
+fn run<T>((capacity, creator): (usize, Peer), mailbox: Mailbox<Command<T>, Bincode>)
+where
+    T: Serialize + DeserializeOwned,
+{
+    let mut state = State {
+        this: mailbox.this(),
+        capacity,
+        buffer: VecDeque::new(),
+        blocked: VecDeque::new(),
+        waiting: VecDeque::new(),
+        receiver: Some(creator),
+        senders: HashSet::new(),
+        had_senders: false,
+        watched: HashSet::new(),
+    };
+    state.watch(creator);
+
+    loop {
+        match mailbox.receive() {
+            Command::Send { value, from, reply, tag } => state.send(value, from, reply, tag),
+            Command::Recv { from, reply, tag } => state.recv(from, reply, tag),
+            Command::Cancel { from, tag } => state.cancel(from, tag),
+            Command::Attach(peer) => state.add_sender(peer),
+            Command::Exited(peer) => state.exited(peer),
+        }
+
+        if state.receiver.is_none() && state.senders.is_empty() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! The node health monitor.
+//!
+//! Each node runs one monitor, started the first time a job is placed from the node.  Every `interval`,
+//! the monitor spawns a small probe process on every other node, which answers back.  Probes that are not
+//! answered within `probe_timeout` (or are answered too slowly) count as failures, and a node with
+//! `max_failures` consecutive failures is quarantined until it answers `recovery` probes in a row again.
+
+use core::time::Duration;
+use std::{collections::HashMap, time::Instant};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, serializer::Bincode, Mailbox, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::singleton;
+
+const NAME: &str = "lucidity::cluster::health";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The weight of the newest sample in the latency average.
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// The configuration of a node's health monitor.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// The interval between probes of each node.
+    pub interval: Duration,
+    /// The amount of time after which an unanswered probe counts as a failure.
+    pub probe_timeout: Duration,
+    /// The probe latency above which an answered probe still counts as a failure, if any.
+    pub max_latency: Option<Duration>,
+    /// The number of consecutive failures after which a node is quarantined.
+    pub max_failures: u32,
+    /// The number of consecutive successes after which a quarantined node is trusted again.
+    pub recovery: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_secs(2),
+            max_latency: None,
+            max_failures: 3,
+            recovery: 2,
+        }
+    }
+}
+
+/// A snapshot of the health of one node, as seen from the local node.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct NodeHealth {
+    /// The node.
+    pub node: u64,
+    /// The smoothed probe latency, if any probe was answered yet.
+    pub latency: Option<Duration>,
+    /// The number of consecutive failures.
+    pub failures: u32,
+    /// Whether the node is excluded from placement.
+    pub quarantined: bool,
+}
+
+struct State {
+    latency: Option<Duration>,
+    failures: u32,
+    successes: u32,
+    quarantined: bool,
+    probe: Option<(u64, Instant)>,
+    probing: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            latency: None,
+            failures: 0,
+            successes: 0,
+            quarantined: false,
+            probe: None,
+            probing: false,
+        }
+    }
+
+    fn failure(&mut self, config: &HealthConfig) {
+        self.failures += 1;
+        self.successes = 0;
+
+        if self.failures >= config.max_failures {
+            self.quarantined = true;
+        }
+    }
+
+    fn success(&mut self, config: &HealthConfig) {
+        self.failures = 0;
+        self.successes += 1;
+
+        if self.quarantined && self.successes >= config.recovery {
+            self.quarantined = false;
+        }
+    }
+}
+
+/// The monitor process.  There is one per node.
+pub(crate) struct HealthMonitor {
+    this: ProcessRef<HealthMonitor>,
+    config: HealthConfig,
+    next_probe: u64,
+    nodes: HashMap<u64, State>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl HealthMonitor {
+    #[init]
+    fn init(config: Config<Self>, health_config: HealthConfig) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.tick();
+
+        Ok(Self {
+            this,
+            config: health_config,
+            next_probe: 0,
+            nodes: HashMap::new(),
+        })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let nodes = lunatic::distributed::nodes();
+
+        // Forget the nodes that left the cluster.
+        self.nodes.retain(|node, _| nodes.contains(node));
+
+        for node in nodes {
+            if node == host::node_id() {
+                continue;
+            }
+
+            let state = self.nodes.entry(node).or_insert_with(State::new);
+
+            // Only one probe per node is in flight at a time.
+            if let Some((probe, sent)) = state.probe {
+                if now.duration_since(sent) < self.config.probe_timeout {
+                    continue;
+                }
+
+                state.failure(&self.config);
+
+                // A prober that is still stuck spawning on a wedged node keeps it from getting another one, and the wait counts
+                // as another failure every `probe_timeout`.
+                if state.probing {
+                    state.probe = Some((probe, now));
+                    continue;
+                }
+
+                state.probe = None;
+            }
+
+            self.next_probe += 1;
+            state.probe = Some((self.next_probe, now));
+            state.probing = true;
+
+            // Spawning on a wedged node may fail, so it happens outside of the monitor.
+            let _ = Process::<(), Bincode>::spawn((self.this, node, self.next_probe), prober);
+        }
+
+        self.this.with_delay(self.config.interval).tick();
+    }
+
+    #[handle_message]
+    fn pong(&mut self, node: u64, probe: u64) {
+        let Some(state) = self.nodes.get_mut(&node) else {
+            return;
+        };
+        let Some((expected, sent)) = state.probe else {
+            return;
+        };
+        if expected != probe {
+            return;
+        }
+
+        let latency = sent.elapsed();
+        state.probe = None;
+        state.latency = Some(match state.latency {
+            Some(average) => average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING),
+            None => latency,
+        });
+
+        match self.config.max_latency {
+            Some(max) if latency > max => state.failure(&self.config),
+            _ => state.success(&self.config),
+        }
+    }
+
+    #[handle_message]
+    fn probed(&mut self, node: u64) {
+        if let Some(state) = self.nodes.get_mut(&node) {
+            state.probing = false;
+        }
+    }
+
+    #[handle_message]
+    fn report_failure(&mut self, node: u64) {
+        if node == host::node_id() {
+            return;
+        }
+
+        self.nodes.entry(node).or_insert_with(State::new).failure(&self.config);
+    }
+
+    #[handle_request]
+    fn configure(&mut self, config: HealthConfig) {
+        self.config = config;
+    }
+
+    #[handle_request]
+    fn health(&self) -> Vec<NodeHealth> {
+        let mut health = self
+            .nodes
+            .iter()
+            .map(|(node, state)| NodeHealth {
+                node: *node,
+                latency: state.latency,
+                failures: state.failures,
+                quarantined: state.quarantined,
+            })
+            .collect::<Vec<_>>();
+        health.sort_by_key(|h| h.node);
+
+        health
+    }
+
+    #[handle_request]
+    fn quarantined(&self) -> Vec<u64> {
+        self.nodes.iter().filter(|(_, state)| state.quarantined).map(|(node, _)| *node).collect()
+    }
+}
+
+fn prober((monitor, node, probe): (ProcessRef<HealthMonitor>, u64, u64), _: Mailbox<(), Bincode>) {
+    let _ = Process::<(), Bincode>::spawn_node(node, (monitor, node, probe), probe_entry);
+    monitor.probed(node);
+}
+
+fn probe_entry((monitor, node, probe): (ProcessRef<HealthMonitor>, u64, u64), _: Mailbox<(), Bincode>) {
+    monitor.pong(node, probe);
+}
+
+/// Returns the connected nodes that are not quarantined by the local health monitor.
+///
+/// If every node is quarantined, none are returned (and placement fails with [`crate::JobError::NodeUnavailable`]).
+pub fn healthy_nodes() -> Vec<u64> {
+    let quarantined = monitor().quarantined();
+
+    lunatic::distributed::nodes().into_iter().filter(|n| !quarantined.contains(n)).collect()
+}
+
+/// Returns whether `node` is currently trusted by the local health monitor.
+pub fn is_healthy(node: u64) -> bool {
+    !monitor().quarantined().contains(&node)
+}
+
+/// Records a failed interaction with `node` (e.g., a timed out start), which counts like a failed probe.
+pub fn report_failure(node: u64) {
+    monitor().report_failure(node);
+}
+
+/// Returns the health of every node known to the local health monitor.
+pub fn health() -> Vec<NodeHealth> {
+    monitor().health()
+}
+
+/// Changes the configuration of the local health monitor.
+pub fn configure_health(config: HealthConfig) {
+    monitor().configure(config);
+}
+
+fn monitor() -> ProcessRef<HealthMonitor> {
+    loop {
+        if let Some(monitor) = singleton::on_node::<HealthMonitor>(host::node_id(), NAME, HealthConfig::default(), LOOKUP_TIMEOUT) {
+            break monitor;
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Cluster membership, with node metadata and join / leave events.
+//!
+//! Each node runs one membership process, started the first time the [`Cluster`] is used on the node.  It
+//! compares [`lunatic::distributed::nodes`] to the nodes it knows about every [`TICK`], spawns a small probe on each
+//! new node to read its [`NodeInfo`], and notifies subscribers once the probe answers (so a joined node is known
+//! to be reachable), or once a node disappears.
+
+use core::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, serializer::Bincode, Mailbox, Process, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::{call, dispatcher, singleton, watch, JobError};
+
+const NAME: &str = "lucidity::cluster::membership";
+const DEMAND_NAME: &str = "lucidity::cluster::demand";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_millis(500);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The id of a node, as used by lunatic.
+pub type NodeId = u64;
+
+/// The attributes of a node.
+///
+/// The attributes are read from the environment of the node: the region from `LUCIDITY_REGION` (or `FLY_REGION`, which fly.io sets), the
+/// labels from `LUCIDITY_LABELS` (e.g., `size=large,gpu=a100`), and the lunatic version from `LUNATIC_VERSION`.  The capacity is the
+/// capacity of the node's dispatcher.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    /// The id of the node.
+    pub id: NodeId,
+    /// The region of the node, if known.
+    pub region: Option<String>,
+    /// The labels of the node.
+    pub labels: BTreeMap<String, String>,
+    /// The number of jobs that may run concurrently on the node.
+    pub capacity: usize,
+    /// The version of the lunatic runtime on the node, if known.
+    pub lunatic_version: Option<String>,
+}
+
+impl NodeInfo {
+    /// Returns the attributes of the local node.
+    pub fn local() -> Self {
+        let labels = call::parse_requirements(&std::env::var("LUCIDITY_LABELS").unwrap_or_default()).into_iter().collect();
+
+        Self {
+            id: host::node_id(),
+            region: std::env::var("LUCIDITY_REGION").or_else(|_| std::env::var("FLY_REGION")).ok(),
+            labels,
+            capacity: dispatcher::stats(host::node_id()).map(|stats| stats.capacity).unwrap_or(dispatcher::DEFAULT_CAPACITY),
+            lunatic_version: std::env::var("LUNATIC_VERSION").ok(),
+        }
+    }
+
+    /// Returns whether the node has every label in `requires`.
+    pub fn satisfies(&self, requires: &[(String, String)]) -> bool {
+        requires.iter().all(|(key, value)| self.label(key) == Some(value.as_str()))
+    }
+
+    /// Returns the value of the label `key`, where `region` is treated as a label too.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        match self.labels.get(key) {
+            Some(value) => Some(value),
+            None if key == "region" => self.region.as_deref(),
+            None => None,
+        }
+    }
+}
+
+/// A change in the membership of the cluster.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClusterEvent {
+    /// A node joined the cluster, and answered a probe.
+    Joined(NodeInfo),
+    /// A node left the cluster.
+    Left(NodeId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct SubscriberId {
+    process: u64,
+    tag: i64,
+}
+
+/// The membership process.  There is one per node.
+pub(crate) struct Membership {
+    this: ProcessRef<Membership>,
+    members: BTreeMap<NodeId, NodeInfo>,
+    probing: HashMap<NodeId, Instant>,
+    discovered: HashMap<NodeId, Instant>,
+    subscribers: HashMap<SubscriberId, (Process<ClusterEvent, Bincode>, Tag)>,
+}
+
+impl Membership {
+    fn publish(&self, event: ClusterEvent) {
+        for (process, tag) in self.subscribers.values() {
+            process.tag_send(*tag, event.clone());
+        }
+    }
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Membership {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.tick();
+
+        Ok(Self {
+            this,
+            members: BTreeMap::new(),
+            probing: HashMap::new(),
+            discovered: HashMap::new(),
+            subscribers: HashMap::new(),
+        })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let nodes = lunatic::distributed::nodes();
+
+        let left = self.members.keys().copied().filter(|node| !nodes.contains(node)).collect::<Vec<_>>();
+        for node in left {
+            self.members.remove(&node);
+            self.publish(ClusterEvent::Left(node));
+        }
+        self.probing.retain(|node, _| nodes.contains(node));
+        self.discovered.retain(|node, _| nodes.contains(node));
+
+        for node in nodes {
+            if node == host::node_id() || self.members.contains_key(&node) {
+                continue;
+            }
+
+            self.discovered.entry(node).or_insert(now);
+
+            // Probe new nodes, and nodes whose last probe went unanswered.
+            if let Some(sent) = self.probing.get(&node) {
+                if now.duration_since(*sent) < PROBE_TIMEOUT {
+                    continue;
+                }
+            }
+
+            self.probing.insert(node, now);
+            let _ = Process::<(), Bincode>::spawn((self.this, node), prober);
+        }
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_message]
+    fn joined(&mut self, info: NodeInfo) {
+        if self.probing.remove(&info.id).is_none() {
+            return;
+        }
+
+        self.members.insert(info.id, info.clone());
+        self.publish(ClusterEvent::Joined(info));
+    }
+
+    #[handle_request]
+    fn members(&self) -> Vec<NodeInfo> {
+        self.members.values().cloned().collect()
+    }
+
+    #[handle_request]
+    fn learning(&mut self, nodes: Vec<NodeId>, max_age: Duration) -> Vec<NodeId> {
+        let now = Instant::now();
+
+        nodes
+            .into_iter()
+            .filter(|node| !self.members.contains_key(node))
+            .filter(|node| now.duration_since(*self.discovered.entry(*node).or_insert(now)) < max_age)
+            .collect()
+    }
+
+    #[handle_request]
+    fn subscribe(&mut self, id: SubscriberId, process: Process<ClusterEvent, Bincode>, tag: Tag) {
+        self.subscribers.insert(id, (process, tag));
+    }
+
+    #[handle_message]
+    fn unsubscribe(&mut self, id: SubscriberId) {
+        self.subscribers.remove(&id);
+    }
+}
+
+/// The counter of the remote placements that found no eligible node.  There is one per cluster, on the anchor node.
+pub(crate) struct Demand {
+    unplaced: u64,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Demand {
+    #[init]
+    fn init(_: Config<Self>, _: ()) -> Result<Self, ()> {
+        Ok(Self { unplaced: 0 })
+    }
+
+    #[handle_message]
+    fn report(&mut self) {
+        self.unplaced += 1;
+    }
+
+    #[handle_request]
+    fn unplaced(&self) -> u64 {
+        self.unplaced
+    }
+}
+
+fn prober((membership, node): (ProcessRef<Membership>, NodeId), _: Mailbox<(), Bincode>) {
+    let _ = Process::<(), Bincode>::spawn_node(node, membership, probe);
+}
+
+fn probe(membership: ProcessRef<Membership>, _: Mailbox<(), Bincode>) {
+    membership.joined(NodeInfo::local());
+}
+
+/// Returns whether `node` is the local node, or a node connected to it.
+pub fn is_connected(node: NodeId) -> bool {
+    node == host::node_id() || lunatic::distributed::nodes().contains(&node)
+}
+
+/// Records that a remote placement found no eligible node (see [`Cluster::unplaced`]).
+pub fn report_unplaced() {
+    if let Some(demand) = singleton::cluster_wide::<Demand>(DEMAND_NAME, (), LOOKUP_TIMEOUT) {
+        demand.report();
+    }
+}
+
+/// Removes a subscription once its process exits.
+#[derive(Serialize, Deserialize)]
+struct Unsubscribe {
+    membership: ProcessRef<Membership>,
+    id: SubscriberId,
+}
+
+impl watch::OnExit for Unsubscribe {
+    fn on_exit(self) {
+        self.membership.unsubscribe(self.id);
+    }
+}
+
+/// The cluster, as seen from the local node.
+#[derive(Clone, Copy, Debug)]
+pub struct Cluster {
+    membership: ProcessRef<Membership>,
+}
+
+impl Cluster {
+    /// Returns the cluster, starting the local membership process if needed.
+    pub fn get() -> Self {
+        let membership = loop {
+            if let Some(membership) = singleton::on_node::<Membership>(host::node_id(), NAME, (), LOOKUP_TIMEOUT) {
+                break membership;
+            }
+        };
+
+        Self { membership }
+    }
+
+    /// Returns the id of the local node.
+    pub fn local_id(&self) -> NodeId {
+        host::node_id()
+    }
+
+    /// Returns the attributes of the local node.
+    pub fn local(&self) -> NodeInfo {
+        NodeInfo::local()
+    }
+
+    /// Returns the remote nodes that joined the cluster (i.e., that answered a probe), ordered by id.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.membership.members()
+    }
+
+    /// Returns the attributes of the remote node `id`, if it joined the cluster.
+    pub fn node(&self, id: NodeId) -> Option<NodeInfo> {
+        self.nodes().into_iter().find(|n| n.id == id)
+    }
+
+    /// Blocks until at least `n` remote nodes joined the cluster, and returns them.
+    ///
+    /// Fails with [`JobError::Timeout`] if that does not happen within `timeout`.
+    pub fn wait_for_nodes(&self, n: usize, timeout: Duration) -> Result<Vec<NodeInfo>, JobError> {
+        let deadline = Some(Instant::now() + timeout);
+
+        loop {
+            let nodes = self.nodes();
+            if nodes.len() >= n {
+                return Ok(nodes);
+            }
+
+            if call::expired(deadline) {
+                return Err(JobError::Timeout);
+            }
+
+            lunatic::sleep(TICK);
+        }
+    }
+
+    /// Blocks until the attributes of every node in `nodes` are known, waiting at most until `timeout` passed since each node was first
+    /// seen, so that every node is only ever waited for once (a node that does not answer in time is left out until it does).
+    pub(crate) fn wait_for_attributes(&self, nodes: &[NodeId], timeout: Duration) {
+        while !self.membership.learning(nodes.to_vec(), timeout).is_empty() {
+            lunatic::sleep(TICK);
+        }
+    }
+
+    /// Returns the number of remote placements, on any node, that found no eligible node.
+    ///
+    /// A changing count means that there is demand for nodes that the cluster does not have (e.g., to start stopped workers again).
+    /// The count lives on the anchor node of the cluster, so it starts over if the anchor node changes.
+    pub fn unplaced(&self) -> u64 {
+        singleton::request::<Demand, _>(DEMAND_NAME, (), LOOKUP_TIMEOUT, |demand| demand.with_timeout(REQUEST_TIMEOUT).unplaced())
+    }
+
+    /// Subscribes the calling process to join and leave events.
+    ///
+    /// The subscription ends when it is dropped, or when the calling process exits.
+    pub fn subscribe(&self) -> Subscription {
+        let tag = Tag::new();
+        let id = SubscriberId {
+            process: host::process_id(),
+            tag: tag.id(),
+        };
+
+        let this = unsafe { Process::<ClusterEvent, Bincode>::this() };
+        self.membership.subscribe(id, this, tag);
+        watch::on_exit(host::node_id(), host::process_id(), Unsubscribe { membership: self.membership, id });
+
+        Subscription { membership: self.membership, id, tag }
+    }
+}
+
+/// A subscription to the join and leave events of the [`Cluster`].
+///
+/// Events are delivered to the mailbox of the process that subscribed, so the subscription must be used from that process.
+#[derive(Debug)]
+pub struct Subscription {
+    membership: ProcessRef<Membership>,
+    id: SubscriberId,
+    tag: Tag,
+}
+
+impl Subscription {
+    /// Blocks until the next event.
+    pub fn next(&self) -> ClusterEvent {
+        loop {
+            if let Some(event) = self.next_timeout(Duration::from_secs(60)) {
+                return event;
+            }
+        }
+    }
+
+    /// Blocks until the next event, or until `timeout` passes.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<ClusterEvent> {
+        let mailbox = unsafe { Mailbox::<ClusterEvent, Bincode>::new() };
+        mailbox.tag_receive_timeout(&[self.tag], timeout).ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.membership.unsubscribe(self.id);
+    }
+}
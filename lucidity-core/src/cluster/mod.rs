@@ -0,0 +1,11 @@
+//! The view of the cluster that `lucidity` uses for placement.
+//!
+//! Placement never trusts [`lunatic::distributed::nodes`] blindly: every generated remote call picks from
+//! [`healthy_nodes`], which leaves out the nodes that the local health monitor has quarantined.  The [`Cluster`]
+//! adds the attributes of each node, and join / leave events, on top of the raw node ids.
+
+mod health;
+mod membership;
+
+pub use health::{configure_health, health, healthy_nodes, is_healthy, report_failure, HealthConfig, NodeHealth};
+pub use membership::{is_connected, report_unplaced, Cluster, ClusterEvent, NodeId, NodeInfo, Subscription};
@@ -0,0 +1,329 @@
+//! The per-node job dispatcher.
+//!
+//! Every generated call asks the dispatcher on the target node for a [`Permit`] before it starts the
+//! service process.  While the node has spare capacity, permits are handed out immediately.  Once the
+//! node is saturated, pending starts are ordered by priority (higher is more urgent), and each pending
+//! start slowly gains priority while it waits, so that low priority jobs are never starved.
+
+use core::time::Duration;
+use std::{cell::Cell, collections::HashSet, time::Instant};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, time::Timeout};
+use serde::{Deserialize, Serialize};
+
+use crate::{call, cluster, singleton, watch, JobError};
+
+/// The default priority of a job.
+pub const DEFAULT_PRIORITY: u32 = 0;
+
+/// The default number of jobs that may run concurrently on a node.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+const NAME: &str = "lucidity::dispatcher";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+thread_local! {
+    static PRIORITY_OVERRIDE: Cell<Option<u32>> = Cell::new(None);
+}
+
+/// The configuration of a node's dispatcher.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DispatcherConfig {
+    /// The number of jobs that may run concurrently on the node.
+    pub capacity: usize,
+    /// The amount of waiting time after which a pending start gains one priority level.
+    pub aging_interval: Duration,
+    /// The amount of time after which a pending start that is no longer polled is dropped.
+    pub pending_lease: Duration,
+}
+
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            aging_interval: Duration::from_secs(1),
+            pending_lease: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A snapshot of a dispatcher's queue.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DispatcherStats {
+    /// The number of job starts waiting for capacity.
+    pub pending: usize,
+    /// The number of jobs currently running.
+    pub running: usize,
+    /// The number of jobs that may run concurrently.
+    pub capacity: usize,
+}
+
+/// A permission to run one job on a node.
+///
+/// The permit is returned to the dispatcher when the process it is attached to exits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Permit {
+    dispatcher: ProcessRef<Dispatcher>,
+    ticket: u64,
+}
+
+impl Permit {
+    /// Attaches the permit to the calling process, so that it is released when the process exits.
+    pub fn attach(&self) {
+        watch::on_exit(host::node_id(), host::process_id(), self.clone());
+    }
+
+    /// Releases the permit immediately.
+    pub fn release(self) {
+        self.dispatcher.release(self.ticket);
+    }
+}
+
+impl watch::OnExit for Permit {
+    fn on_exit(self) {
+        self.release();
+    }
+}
+
+/// The answer of the dispatcher to a poll of a pending start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Acquire {
+    /// The start may go ahead.
+    Granted,
+    /// The start must keep waiting for capacity.
+    Waiting,
+    /// The dispatcher does not know the ticket (e.g., it was not polled within the pending lease), so the start must be enqueued again.
+    Unknown,
+}
+
+struct Pending {
+    ticket: u64,
+    priority: u32,
+    enqueued: Instant,
+    polled: Instant,
+}
+
+/// The dispatcher process.  There is one per node, started the first time a job targets the node.
+pub(crate) struct Dispatcher {
+    config: DispatcherConfig,
+    next_ticket: u64,
+    pending: Vec<Pending>,
+    running: HashSet<u64>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Dispatcher {
+    #[init]
+    fn init(_: Config<Self>, config: DispatcherConfig) -> Result<Self, ()> {
+        Ok(Self {
+            config,
+            next_ticket: 0,
+            pending: Vec::new(),
+            running: HashSet::new(),
+        })
+    }
+
+    #[handle_request]
+    fn enqueue(&mut self, priority: u32) -> u64 {
+        let now = Instant::now();
+
+        self.next_ticket += 1;
+        self.pending.push(Pending {
+            ticket: self.next_ticket,
+            priority,
+            enqueued: now,
+            polled: now,
+        });
+
+        self.next_ticket
+    }
+
+    #[handle_request]
+    fn try_acquire(&mut self, ticket: u64) -> Acquire {
+        // A retried poll may ask about a ticket that was already granted.
+        if self.running.contains(&ticket) {
+            return Acquire::Granted;
+        }
+
+        let now = Instant::now();
+        let lease = self.config.pending_lease;
+        match self.pending.iter_mut().find(|p| p.ticket == ticket) {
+            Some(pending) => pending.polled = now,
+            None => return Acquire::Unknown,
+        }
+        self.pending.retain(|p| now.duration_since(p.polled) < lease);
+
+        if self.running.len() >= self.config.capacity {
+            return Acquire::Waiting;
+        }
+
+        let aging = self.config.aging_interval.as_secs_f64().max(f64::EPSILON);
+        let effective = |p: &Pending| p.priority as f64 + now.duration_since(p.enqueued).as_secs_f64() / aging;
+        let best = self
+            .pending
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| effective(a).total_cmp(&effective(b)).then(b.enqueued.cmp(&a.enqueued)))
+            .map(|(k, p)| (k, p.ticket));
+
+        match best {
+            Some((k, best)) if best == ticket => {
+                self.pending.swap_remove(k);
+                self.running.insert(ticket);
+                Acquire::Granted
+            }
+            _ => Acquire::Waiting,
+        }
+    }
+
+    #[handle_message]
+    fn release(&mut self, ticket: u64) {
+        self.running.remove(&ticket);
+        self.pending.retain(|p| p.ticket != ticket);
+    }
+
+    #[handle_request]
+    fn configure(&mut self, config: DispatcherConfig) {
+        self.config = config;
+    }
+
+    #[handle_request]
+    fn stats(&self) -> DispatcherStats {
+        DispatcherStats {
+            pending: self.pending.len(),
+            running: self.running.len(),
+            capacity: self.config.capacity,
+        }
+    }
+}
+
+/// Waits until the dispatcher on `node` allows a job with `priority` to start.
+///
+/// The dispatcher is polled every `retry_interval`, until `deadline` (if any) passes, until `node` is quarantined
+/// by the health monitor while it does not answer, or until `node` leaves the cluster.  A start whose ticket the
+/// dispatcher no longer knows (e.g., because the dispatcher was restarted) is enqueued again.
+pub fn acquire(node: u64, priority: u32, retry_interval: Duration, deadline: Option<Instant>) -> Result<Permit, JobError> {
+    let mut dispatcher = lookup(node, deadline)?;
+
+    loop {
+        let ticket = loop {
+            if call::expired(deadline) {
+                return Err(JobError::Timeout);
+            }
+
+            if let Ok(ticket) = dispatcher.with_timeout(retry_interval).enqueue(priority) {
+                break ticket;
+            }
+
+            unresponsive(node)?;
+            dispatcher = lookup(node, deadline)?;
+        };
+
+        loop {
+            if call::expired(deadline) {
+                return Err(JobError::Timeout);
+            }
+
+            match dispatcher.with_timeout(retry_interval).try_acquire(ticket) {
+                Ok(Acquire::Granted) => return Ok(Permit { dispatcher, ticket }),
+                Ok(Acquire::Waiting) => lunatic::sleep(retry_interval),
+                Ok(Acquire::Unknown) => break,
+                Err(_) => {
+                    unresponsive(node)?;
+
+                    // A restarted dispatcher lost its queue, so the start is enqueued with the new one.
+                    let current = lookup(node, deadline)?;
+                    if current != dispatcher {
+                        dispatcher = current;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the configuration of the dispatcher on `node`.
+///
+/// Fails if `node` is not connected, or is quarantined while its dispatcher does not answer.
+pub fn configure(node: u64, config: DispatcherConfig) -> Result<(), JobError> {
+    request(node, |dispatcher| dispatcher.with_timeout(LOOKUP_TIMEOUT).configure(config))
+}
+
+/// Returns a snapshot of the queue of the dispatcher on `node`.
+///
+/// Fails if `node` is not connected, or is quarantined while its dispatcher does not answer.
+pub fn stats(node: u64) -> Result<DispatcherStats, JobError> {
+    request(node, |dispatcher| dispatcher.with_timeout(LOOKUP_TIMEOUT).stats())
+}
+
+/// Like [`stats`], but returns `None` if `node` is not connected, or its dispatcher does not answer within `timeout` (e.g., while the node
+/// stops).
+pub fn stats_timeout(node: u64, timeout: Duration) -> Option<DispatcherStats> {
+    if !cluster::is_connected(node) {
+        return None;
+    }
+
+    let dispatcher = singleton::on_node::<Dispatcher>(node, NAME, DispatcherConfig::default(), timeout)?;
+
+    dispatcher.with_timeout(timeout).stats().ok()
+}
+
+/// Runs `f` with every job started from it (in the calling process) using `priority`, rather than
+/// the `priority` set on the job attribute.
+pub fn with_priority<R>(priority: u32, f: impl FnOnce() -> R) -> R {
+    let previous = PRIORITY_OVERRIDE.with(|p| p.replace(Some(priority)));
+    let result = f();
+    PRIORITY_OVERRIDE.with(|p| p.set(previous));
+
+    result
+}
+
+/// Returns the priority set by an enclosing [`with_priority`], or `default` if there is none.
+pub fn priority_or(default: u32) -> u32 {
+    PRIORITY_OVERRIDE.with(|p| p.get()).unwrap_or(default)
+}
+
+fn unresponsive(node: u64) -> Result<(), JobError> {
+    if !cluster::is_connected(node) {
+        return Err(JobError::UnknownNode(node));
+    }
+
+    cluster::report_failure(node);
+
+    if cluster::is_healthy(node) {
+        Ok(())
+    } else {
+        Err(JobError::NodeUnavailable(node))
+    }
+}
+
+/// Returns the dispatcher on `node`, until `deadline` (if any) passes, or `node` is quarantined, or leaves the cluster.
+fn lookup(node: u64, deadline: Option<Instant>) -> Result<ProcessRef<Dispatcher>, JobError> {
+    if !cluster::is_connected(node) {
+        return Err(JobError::UnknownNode(node));
+    }
+
+    loop {
+        if call::expired(deadline) {
+            return Err(JobError::Timeout);
+        }
+
+        if let Some(dispatcher) = singleton::on_node::<Dispatcher>(node, NAME, DispatcherConfig::default(), LOOKUP_TIMEOUT) {
+            break Ok(dispatcher);
+        }
+
+        // An unresponsive node must not hold the caller forever.
+        unresponsive(node)?;
+    }
+}
+
+fn request<R>(node: u64, request: impl Fn(ProcessRef<Dispatcher>) -> Result<R, Timeout>) -> Result<R, JobError> {
+    loop {
+        if let Ok(answer) = request(lookup(node, None)?) {
+            break Ok(answer);
+        }
+
+        unresponsive(node)?;
+    }
+}
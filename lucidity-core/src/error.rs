@@ -0,0 +1,47 @@
+//! The error type for job calls.
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// The error returned by the fallible `lucidity` calls (e.g., the generated call builder).
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobError {
+    /// The service process failed to start.
+    Startup(String),
+    /// The call did not complete before its timeout.
+    Timeout,
+    /// The node was quarantined by the health monitor while the call was trying to start on it.
+    NodeUnavailable(u64),
+    /// The node is not (or no longer) connected to the cluster.
+    UnknownNode(u64),
+    /// No node satisfies the placement requirements of the call.
+    NoEligibleNode,
+    /// The worker of the job died without a result, and was not restarted (anymore).
+    Failed {
+        /// The number of restarts before the job failed.
+        restarts: u32,
+    },
+    /// The name is already registered to another process.
+    NameTaken(String),
+    /// The name is registered to a different type of handle.
+    WrongType(String),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Startup(e) => write!(f, "The service process failed to start.  {}", e),
+            JobError::Timeout => write!(f, "The call timed out."),
+            JobError::NodeUnavailable(node) => write!(f, "The node {} is unavailable.", node),
+            JobError::UnknownNode(node) => write!(f, "The node {} is not connected to the cluster.", node),
+            JobError::NoEligibleNode => write!(f, "No node satisfies the placement requirements of the call."),
+            JobError::Failed { restarts } => write!(f, "The job failed after {} restart(s).", restarts),
+            JobError::NameTaken(name) => write!(f, "The name `{}` is already registered.", name),
+            JobError::WrongType(name) => write!(f, "The name `{}` is registered to a different type.", name),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
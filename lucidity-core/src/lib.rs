@@ -10,6 +10,26 @@ pub use lunatic;
 pub use rand;
 pub use serde;
 
+pub use call::{CallOptions, Fanout, Speculation};
+pub use error::JobError;
+pub use supervision::{Restart, RestartNode};
+
+pub mod actor;
+pub mod call;
+pub mod channel;
+pub mod cluster;
+pub mod dispatcher;
+pub mod limits;
+pub mod pubsub;
+pub mod registry;
+pub mod sharding;
+pub mod supervision;
+pub mod sync;
+
+mod error;
+mod singleton;
+mod watch;
+
 /// A job is a process that can be spawned and shutdown.
 ///
 /// This type is usually created with the [`lucidity::job`] macro on the async methods.
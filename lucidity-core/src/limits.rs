@@ -0,0 +1,224 @@
+//! Cluster-wide rate limits and concurrency caps for jobs.
+//!
+//! Jobs declared with `rate_limit` or `max_concurrency` ask a single coordinator process (running on the
+//! anchor node of the cluster) for a [`Lease`] before they are spawned.  The coordinator keeps a token
+//! bucket and a semaphore per job, and the lease is returned once the job's service process exits.
+
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::{call, singleton, watch, JobError};
+
+const NAME: &str = "lucidity::limits";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The limits that apply to one job across the whole cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// The maximum number of starts per second.
+    pub rate_limit: Option<f64>,
+    /// The maximum number of instances running at the same time.
+    pub max_concurrency: Option<usize>,
+}
+
+/// The identity of a lease, unique across the cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct LeaseId {
+    node: u64,
+    process: u64,
+    tag: i64,
+}
+
+/// A permission to run one instance of a rate limited, or concurrency capped, job.
+///
+/// The lease is returned to the coordinator when the process it is attached to exits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lease {
+    limiter: ProcessRef<Limiter>,
+    job: String,
+    id: LeaseId,
+}
+
+impl Lease {
+    /// Attaches the lease to the calling process, so that it is released when the process exits.
+    pub fn attach(&self) {
+        self.limiter.attached(self.job.clone(), self.id);
+        watch::on_exit(host::node_id(), host::process_id(), self.clone());
+    }
+
+    /// Releases the lease immediately.
+    pub fn release(self) {
+        self.limiter.release(self.job, self.id);
+    }
+}
+
+impl watch::OnExit for Lease {
+    fn on_exit(self) {
+        self.release();
+    }
+}
+
+struct JobState {
+    limits: Limits,
+    tokens: f64,
+    refilled: Instant,
+    running: HashSet<LeaseId>,
+    unattached: HashMap<LeaseId, Instant>,
+}
+
+impl JobState {
+    fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            tokens: limits.rate_limit.map(|r| r.max(1.0)).unwrap_or_default(),
+            refilled: Instant::now(),
+            running: HashSet::new(),
+            unattached: HashMap::new(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.limits.rate_limit {
+            // Allow bursts of up to one second worth of starts.
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        }
+
+        self.refilled = now;
+    }
+
+    fn reclaim(&mut self, now: Instant) {
+        // Leases that were granted, but never attached to a service, belong to callers that gave up.
+        let expired = self
+            .unattached
+            .iter()
+            .filter(|(_, granted)| now.duration_since(**granted) > ATTACH_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            self.unattached.remove(&id);
+            self.running.remove(&id);
+        }
+    }
+}
+
+/// The coordinator process.  There is one per cluster, started the first time a limited job is called.
+pub(crate) struct Limiter {
+    jobs: HashMap<String, JobState>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Limiter {
+    #[init]
+    fn init(_: Config<Self>, _: ()) -> Result<Self, ()> {
+        Ok(Self { jobs: HashMap::new() })
+    }
+
+    #[handle_request]
+    fn try_acquire(&mut self, job: String, limits: Limits, id: LeaseId) -> bool {
+        let now = Instant::now();
+        let state = self.jobs.entry(job).or_insert_with(|| JobState::new(limits));
+
+        // A retried request may ask about a lease that was already granted.
+        if state.running.contains(&id) {
+            return true;
+        }
+
+        if state.limits != limits {
+            state.limits = limits;
+        }
+
+        state.refill(now);
+        state.reclaim(now);
+
+        if let Some(max) = state.limits.max_concurrency {
+            if state.running.len() >= max {
+                return false;
+            }
+        }
+
+        if state.limits.rate_limit.is_some() {
+            if state.tokens < 1.0 {
+                return false;
+            }
+
+            state.tokens -= 1.0;
+        }
+
+        state.running.insert(id);
+        state.unattached.insert(id, now);
+
+        true
+    }
+
+    #[handle_message]
+    fn attached(&mut self, job: String, id: LeaseId) {
+        if let Some(state) = self.jobs.get_mut(&job) {
+            state.unattached.remove(&id);
+        }
+    }
+
+    #[handle_message]
+    fn release(&mut self, job: String, id: LeaseId) {
+        if let Some(state) = self.jobs.get_mut(&job) {
+            state.unattached.remove(&id);
+            state.running.remove(&id);
+        }
+    }
+
+    #[handle_request]
+    fn running(&self, job: String) -> usize {
+        self.jobs.get(&job).map(|s| s.running.len()).unwrap_or_default()
+    }
+}
+
+/// Waits until the cluster-wide `limits` of `job` allow another instance to start.
+///
+/// The coordinator is polled every `retry_interval`, until `deadline` (if any) passes.  A coordinator that does not
+/// answer is looked up again, in case it died, or the anchor node left the cluster.
+pub fn acquire(job: &str, limits: Limits, retry_interval: Duration, deadline: Option<Instant>) -> Result<Lease, JobError> {
+    let mut limiter = limiter();
+    let id = LeaseId {
+        node: host::node_id(),
+        process: host::process_id(),
+        tag: Tag::new().id(),
+    };
+
+    loop {
+        if call::expired(deadline) {
+            return Err(JobError::Timeout);
+        }
+
+        match limiter.with_timeout(retry_interval).try_acquire(job.to_owned(), limits, id) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(_) => limiter = singleton::resolve::<Limiter>(NAME, (), LOOKUP_TIMEOUT).unwrap_or(limiter),
+        }
+
+        lunatic::sleep(retry_interval);
+    }
+
+    Ok(Lease { limiter, job: job.to_owned(), id })
+}
+
+/// Returns the number of instances of `job` currently holding a lease.
+pub fn running(job: &str) -> usize {
+    singleton::request::<Limiter, _>(NAME, (), LOOKUP_TIMEOUT, |limiter| limiter.with_timeout(REQUEST_TIMEOUT).running(job.to_owned()))
+}
+
+fn limiter() -> ProcessRef<Limiter> {
+    loop {
+        if let Some(limiter) = singleton::cluster_wide::<Limiter>(NAME, (), LOOKUP_TIMEOUT) {
+            break limiter;
+        }
+    }
+}
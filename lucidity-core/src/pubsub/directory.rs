@@ -0,0 +1,132 @@
+//! The directory of the relays with subscribers, per topic.
+
+use core::time::Duration;
+use std::collections::HashMap;
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, time::Timeout};
+
+use super::relay::{Relay, RelayMessages};
+use crate::{cluster, cluster::NodeId, singleton};
+
+const NAME: &str = "lucidity::pubsub::directory";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_secs(1);
+
+/// The directory process.  There is one per cluster.
+pub(crate) struct Directory {
+    this: ProcessRef<Directory>,
+    topics: HashMap<String, HashMap<NodeId, ProcessRef<Relay>>>,
+    followers: HashMap<String, HashMap<NodeId, ProcessRef<Relay>>>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Directory {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.with_delay(TICK).tick();
+
+        Ok(Self {
+            this,
+            topics: HashMap::new(),
+            followers: HashMap::new(),
+        })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        let mut changed = Vec::new();
+
+        // The relays on a node that left the cluster are gone with it.
+        for (topic, relays) in self.topics.iter_mut() {
+            let before = relays.len();
+            relays.retain(|node, _| cluster::is_connected(*node));
+
+            if relays.len() != before {
+                changed.push(topic.clone());
+            }
+        }
+
+        for followers in self.followers.values_mut() {
+            followers.retain(|node, _| cluster::is_connected(*node));
+        }
+
+        for topic in changed {
+            self.push(&topic);
+        }
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_request]
+    fn join(&mut self, topic: String, node: NodeId, relay: ProcessRef<Relay>) {
+        self.topics.entry(topic.clone()).or_default().insert(node, relay);
+        self.push(&topic);
+    }
+
+    #[handle_message]
+    fn leave(&mut self, topic: String, node: NodeId) {
+        if let Some(relays) = self.topics.get_mut(&topic) {
+            relays.remove(&node);
+
+            if relays.is_empty() {
+                self.topics.remove(&topic);
+            }
+        }
+
+        self.push(&topic);
+    }
+
+    #[handle_request]
+    fn follow(&mut self, topic: String, node: NodeId, relay: ProcessRef<Relay>) -> Vec<ProcessRef<Relay>> {
+        self.followers.entry(topic.clone()).or_default().insert(node, relay);
+
+        self.relays(&topic)
+    }
+
+    #[handle_request]
+    fn ping(&self) {}
+
+    #[handle_request]
+    fn nodes(&self, topic: String) -> Vec<NodeId> {
+        let mut nodes = self.topics.get(&topic).map(|r| r.keys().copied().collect::<Vec<_>>()).unwrap_or_default();
+        nodes.sort();
+
+        nodes
+    }
+}
+
+impl Directory {
+    fn relays(&self, topic: &str) -> Vec<ProcessRef<Relay>> {
+        self.topics.get(topic).map(|r| r.values().copied().collect()).unwrap_or_default()
+    }
+
+    /// Sends the relays of `topic` to the relays that publish on it.
+    fn push(&self, topic: &str) {
+        let relays = self.relays(topic);
+
+        for follower in self.followers.get(topic).into_iter().flat_map(|f| f.values()) {
+            follower.routes(topic.to_owned(), relays.clone());
+        }
+    }
+}
+
+/// Sends `request` to the directory, looking the directory up again whenever it does not answer.
+pub(crate) fn request<R>(request: impl Fn(ProcessRef<Directory>) -> Result<R, Timeout>) -> R {
+    singleton::request::<Directory, R>(NAME, (), LOOKUP_TIMEOUT, request)
+}
+
+/// Returns the directory, if it answers.
+///
+/// The cached reference is checked first, and the directory is looked up again, bypassing the cache, if it does not answer (e.g., because
+/// it died, and no request from this node noticed yet).
+pub(crate) fn current() -> Option<ProcessRef<Directory>> {
+    if let Some(directory) = singleton::cluster_wide::<Directory>(NAME, (), LOOKUP_TIMEOUT) {
+        if directory.with_timeout(REQUEST_TIMEOUT).ping().is_ok() {
+            return Some(directory);
+        }
+    }
+
+    singleton::resolve::<Directory>(NAME, (), LOOKUP_TIMEOUT)
+}
@@ -0,0 +1,17 @@
+//! Named publish / subscribe topics across the cluster.
+//!
+//! Each node runs one relay, started the first time the node publishes, or subscribes.  A relay delivers the messages of a
+//! topic to the subscribers on its node, and a single directory process (running on the anchor node of the cluster) keeps
+//! track of which relays have subscribers for which topics.  A published message is sent to the local relay, which sends it
+//! once to every relay with subscribers for the topic, so that it crosses the network once per node, rather than once per
+//! subscriber.  The relays keep the directory up to date, so when the directory starts again (e.g., on a new anchor node), the relays
+//! join, and follow, their topics again within a tick.  While a relay learns where a topic is subscribed (e.g., while the directory does
+//! not answer), it queues up to 1024 messages of the topic, and drops the rest.
+//!
+//! Messages are delivered into the mailbox of the subscribing process, tagged with the tag of its [`Subscription`].  The
+//! messages of one publisher arrive in the order they were published.
+
+mod directory;
+mod relay;
+
+pub use relay::{local_subscribers, nodes, publish, subscribe, Subscription};
@@ -0,0 +1,328 @@
+//! The per-node relays, and the publish / subscribe API on top of them.
+
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    marker::PhantomData,
+};
+
+use lunatic::{
+    abstract_process,
+    ap::{Config, ProcessRef},
+    host,
+    serializer::{Bincode, CanSerialize, DecodeError, EncodeError, MessageRw},
+    Mailbox, Process, Tag,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::directory::{self, request, Directory, DirectoryMessages, DirectoryRequests, REQUEST_TIMEOUT};
+use crate::{cluster::NodeId, singleton, JobError};
+
+const NAME: &str = "lucidity::pubsub::relay";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_secs(1);
+
+/// The number of messages of a topic that a relay keeps while it learns the routes of the topic.  Later messages are dropped.
+const MAX_QUEUED: usize = 1024;
+
+/// Sends bytes that are already serialized with bincode as they are, so that the receiver decodes them as the original type.
+struct Raw;
+
+impl CanSerialize<Vec<u8>> for Raw {
+    fn encode(message: &Vec<u8>) -> Result<(), EncodeError> {
+        Ok(MessageRw {}.write_all(message)?)
+    }
+
+    fn decode() -> Result<Vec<u8>, DecodeError> {
+        let mut message = Vec::new();
+        MessageRw {}.read_to_end(&mut message)?;
+
+        Ok(message)
+    }
+}
+
+/// The relay process.  There is one per node.
+///
+/// The relay never waits on the directory for long: it learns the routes of a topic from a helper process, and queues the messages
+/// of the topic meanwhile.  On every tick, another helper checks the directory, and, once it changed (e.g., it started again, empty,
+/// on a new anchor node), joins the subscribed topics, and follows the published topics, again.
+pub(crate) struct Relay {
+    this: ProcessRef<Relay>,
+    directory: Option<ProcessRef<Directory>>,
+    syncing: bool,
+    subscribers: HashMap<String, HashSet<(u64, Tag)>>,
+    routes: HashMap<String, Vec<ProcessRef<Relay>>>,
+    queued: HashMap<String, Vec<Vec<u8>>>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Relay {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.with_delay(TICK).tick();
+
+        Ok(Self {
+            this,
+            directory: None,
+            syncing: false,
+            subscribers: HashMap::new(),
+            routes: HashMap::new(),
+            queued: HashMap::new(),
+        })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        let mut emptied = Vec::new();
+
+        for (topic, subscribers) in self.subscribers.iter_mut() {
+            subscribers.retain(|(process, _)| is_alive(*process));
+
+            if subscribers.is_empty() {
+                emptied.push(topic.clone());
+            }
+        }
+
+        for topic in emptied {
+            self.subscribers.remove(&topic);
+            self.leave(topic);
+        }
+
+        let idle = self.subscribers.is_empty() && self.routes.is_empty();
+        if !self.syncing && !idle {
+            self.syncing = true;
+
+            let joined = self.subscribers.keys().cloned().collect::<Vec<_>>();
+            let followed = self.routes.keys().cloned().collect::<Vec<_>>();
+            Process::<(), Bincode>::spawn((self.this, self.directory, joined, followed), sync);
+        }
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_message]
+    fn synced(&mut self, directory: Option<ProcessRef<Directory>>) {
+        self.syncing = false;
+        self.directory = directory;
+    }
+
+    #[handle_request]
+    fn subscribe(&mut self, topic: String, process: u64, tag: Tag) {
+        let subscribers = self.subscribers.entry(topic.clone()).or_default();
+        subscribers.insert((process, tag));
+
+        // Join before answering, so that the messages published after the subscription returns reach it.  If the directory does not
+        // answer, the next sync joins the topic instead.
+        if subscribers.len() == 1 {
+            let joined = self
+                .directory
+                .or_else(directory::current)
+                .map_or(false, |d| d.with_timeout(REQUEST_TIMEOUT).join(topic, host::node_id(), self.this).is_ok());
+
+            if !joined {
+                self.directory = None;
+            }
+        }
+    }
+
+    #[handle_message]
+    fn unsubscribe(&mut self, topic: String, process: u64, tag: Tag) {
+        if let Some(subscribers) = self.subscribers.get_mut(&topic) {
+            subscribers.remove(&(process, tag));
+
+            if subscribers.is_empty() {
+                self.subscribers.remove(&topic);
+                self.leave(topic);
+            }
+        }
+    }
+
+    #[handle_message]
+    fn publish(&mut self, topic: String, payload: Vec<u8>) {
+        if let Some(relays) = self.routes.get(&topic) {
+            for relay in relays {
+                relay.deliver(topic.clone(), payload.clone());
+            }
+
+            return;
+        }
+
+        // The routes are learned by a helper, so that the relay keeps delivering while the directory does not answer.  The directory
+        // pushes later changes of the routes of a followed topic.
+        let queued = self.queued.entry(topic.clone()).or_default();
+        if queued.is_empty() {
+            Process::<(), Bincode>::spawn((self.this, topic), follow);
+        }
+        if queued.len() < MAX_QUEUED {
+            queued.push(payload);
+        }
+    }
+
+    #[handle_message]
+    fn routes(&mut self, topic: String, relays: Vec<ProcessRef<Relay>>) {
+        for payload in self.queued.remove(&topic).into_iter().flatten() {
+            for relay in &relays {
+                relay.deliver(topic.clone(), payload.clone());
+            }
+        }
+
+        self.routes.insert(topic, relays);
+    }
+
+    #[handle_message]
+    fn deliver(&mut self, topic: String, payload: Vec<u8>) {
+        for (process, tag) in self.subscribers.get(&topic).into_iter().flatten() {
+            let subscriber = unsafe { Process::<Vec<u8>, Raw>::new(host::node_id(), *process) };
+            subscriber.tag_send(*tag, payload.clone());
+        }
+    }
+
+    #[handle_request]
+    fn subscribers(&self, topic: String) -> usize {
+        self.subscribers.get(&topic).map(|s| s.len()).unwrap_or_default()
+    }
+}
+
+impl Relay {
+    fn leave(&self, topic: String) {
+        // A relay that does not know the directory has nothing to leave: the next directory it syncs with only learns its current topics.
+        if let Some(directory) = self.directory {
+            directory.leave(topic, host::node_id());
+        }
+    }
+}
+
+/// Learns the routes of `topic` for `relay`, and follows the topic.
+fn follow((relay, topic): (ProcessRef<Relay>, String), _: Mailbox<(), Bincode>) {
+    let routes = request(|d| d.with_timeout(REQUEST_TIMEOUT).follow(topic.clone(), host::node_id(), relay));
+
+    relay.routes(topic, routes);
+}
+
+/// Checks the directory for `relay`, and, if it is not the `known` one, joins the `joined` topics, and follows the `followed` topics
+/// again, since a new directory starts empty.
+fn sync((relay, known, joined, followed): (ProcessRef<Relay>, Option<ProcessRef<Directory>>, Vec<String>, Vec<String>), _: Mailbox<(), Bincode>) {
+    let node = host::node_id();
+    let Some(directory) = directory::current() else {
+        return relay.synced(None);
+    };
+
+    if Some(directory) != known {
+        for topic in joined {
+            if directory.with_timeout(REQUEST_TIMEOUT).join(topic, node, relay).is_err() {
+                return relay.synced(None);
+            }
+        }
+
+        for topic in followed {
+            match directory.with_timeout(REQUEST_TIMEOUT).follow(topic.clone(), node, relay) {
+                Ok(routes) => relay.routes(topic, routes),
+                Err(_) => return relay.synced(None),
+            }
+        }
+    }
+
+    relay.synced(Some(directory));
+}
+
+fn is_alive(process: u64) -> bool {
+    unsafe { Process::<(), Bincode>::new(host::node_id(), process) }.is_alive()
+}
+
+/// Publishes `message` to every subscriber of `topic`, on any node.
+///
+/// The subscribers must subscribe with the same type `T`.
+pub fn publish<T>(topic: &str, message: &T)
+where
+    T: Serialize,
+{
+    let payload = bincode::serialize(message).unwrap();
+
+    relay().publish(topic.to_owned(), payload);
+}
+
+/// Subscribes the calling process to `topic`.
+///
+/// The messages are delivered until the subscription is dropped, or the calling process exits.
+pub fn subscribe<T>(topic: &str) -> Subscription<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let relay = relay();
+    let tag = Tag::new();
+
+    relay.subscribe(topic.to_owned(), host::process_id(), tag);
+
+    Subscription {
+        relay,
+        topic: topic.to_owned(),
+        tag,
+        message: PhantomData,
+    }
+}
+
+/// Returns the nodes that have subscribers for `topic`.
+pub fn nodes(topic: &str) -> Vec<NodeId> {
+    request(|d| d.with_timeout(REQUEST_TIMEOUT).nodes(topic.to_owned()))
+}
+
+/// Returns the number of subscribers for `topic` on the local node.
+pub fn local_subscribers(topic: &str) -> usize {
+    relay().subscribers(topic.to_owned())
+}
+
+/// The subscription of a process to a topic.  The process is unsubscribed when the subscription is dropped.
+pub struct Subscription<T> {
+    relay: ProcessRef<Relay>,
+    topic: String,
+    tag: Tag,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> Subscription<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Returns the topic of the subscription.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Returns the tag of the messages of the subscription, e.g., to receive them with `Mailbox::tag_receive`.
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Blocks until the next message of the topic arrives.
+    pub fn recv(&self) -> T {
+        let mailbox = unsafe { Mailbox::<T, Bincode>::new() };
+        mailbox.tag_receive(&[self.tag])
+    }
+
+    /// Blocks until the next message of the topic arrives, or fails with [`JobError::Timeout`] once `timeout` passes.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, JobError> {
+        let mailbox = unsafe { Mailbox::<T, Bincode>::new() };
+        mailbox.tag_receive_timeout(&[self.tag], timeout).map_err(|_| JobError::Timeout)
+    }
+
+    /// Returns an iterator over the messages of the topic, which blocks while there are none.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::repeat_with(|| self.recv())
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.relay.unsubscribe(self.topic.clone(), host::process_id(), self.tag);
+    }
+}
+
+fn relay() -> ProcessRef<Relay> {
+    loop {
+        if let Some(relay) = singleton::on_node::<Relay>(host::node_id(), NAME, (), LOOKUP_TIMEOUT) {
+            break relay;
+        }
+    }
+}
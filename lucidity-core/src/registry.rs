@@ -0,0 +1,205 @@
+//! A cluster-wide registry of named jobs and actors.
+//!
+//! Lunatic registries are node local, so the names live in a single registry process, running on the anchor
+//! node of the cluster.  A name is bound to one process until it is unregistered, the process exits, or the node
+//! of the process leaves the cluster.  Lookups are typed: the registry remembers the type of the handle that was
+//! registered, and only hands the process back as that type.
+
+use core::time::Duration;
+use std::collections::HashMap;
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, time::Timeout, AbstractProcess};
+use serde::{Deserialize, Serialize};
+
+use crate::{cluster, cluster::NodeId, singleton, watch, JobError};
+
+const NAME: &str = "lucidity::registry";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_secs(1);
+
+/// A handle to a job or an actor that can be registered under a name.
+///
+/// The generated job and actor types implement this trait.
+pub trait Registrable {
+    /// The handle that lookups of the name return.
+    type Handle;
+
+    /// Returns the node, and the id, of the process behind the handle.
+    fn process(&self) -> (NodeId, u64);
+
+    /// Creates a handle to the process `process` on `node`.
+    fn from_process(node: NodeId, process: u64) -> Self::Handle;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    handle_type: String,
+    node: NodeId,
+    process: u64,
+}
+
+/// The registry process.  There is one per cluster, started the first time a name is registered, or looked up.
+pub(crate) struct Registry {
+    this: ProcessRef<Registry>,
+    entries: HashMap<String, Entry>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Registry {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.with_delay(TICK).tick();
+
+        Ok(Self { this, entries: HashMap::new() })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        // The sentinels of the processes on a node that left the cluster are gone with it.
+        self.entries.retain(|_, entry| cluster::is_connected(entry.node));
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_request]
+    fn bind(&mut self, name: String, handle_type: String, node: NodeId, process: u64) -> Result<(), JobError> {
+        if let Some(existing) = self.entries.get(&name) {
+            // A retried request must not conflict with itself.
+            if existing.node == node && existing.process == process {
+                return Ok(());
+            }
+
+            return Err(JobError::NameTaken(name));
+        }
+
+        self.entries.insert(name, Entry { handle_type, node, process });
+
+        Ok(())
+    }
+
+    #[handle_message]
+    fn unbind(&mut self, name: String) {
+        self.entries.remove(&name);
+    }
+
+    #[handle_message]
+    fn exited(&mut self, name: String, node: NodeId, process: u64) {
+        // The name may have been bound to another process since.
+        if let Some(entry) = self.entries.get(&name) {
+            if entry.node == node && entry.process == process {
+                self.entries.remove(&name);
+            }
+        }
+    }
+
+    #[handle_request]
+    fn resolve(&self, name: String) -> Option<Entry> {
+        self.entries.get(&name).cloned()
+    }
+
+    #[handle_request]
+    fn names(&self) -> Vec<String> {
+        let mut names = self.entries.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+}
+
+/// Unregisters a name once its process exits.
+#[derive(Serialize, Deserialize)]
+struct Unregister {
+    registry: ProcessRef<Registry>,
+    name: String,
+    node: NodeId,
+    process: u64,
+}
+
+impl watch::OnExit for Unregister {
+    fn on_exit(self) {
+        self.registry.exited(self.name, self.node, self.process);
+    }
+}
+
+/// Registers `value` under `name`, until it is unregistered, or its process exits.
+///
+/// Fails with [`JobError::NameTaken`] if the name is bound to another process.
+pub fn register<R: Registrable>(name: &str, value: &R) -> Result<(), JobError> {
+    let (node, process) = value.process();
+
+    request(|registry| {
+        registry
+            .with_timeout(REQUEST_TIMEOUT)
+            .bind(name.to_owned(), std::any::type_name::<R::Handle>().to_owned(), node, process)
+    })?;
+
+    // The request updates the cached reference if it had to look the registry up again.
+    let registry = registry();
+    watch::on_exit(
+        node,
+        process,
+        Unregister {
+            registry,
+            name: name.to_owned(),
+            node,
+            process,
+        },
+    );
+
+    Ok(())
+}
+
+/// Removes the binding of `name`, if any.
+pub fn unregister(name: &str) {
+    registry().unbind(name.to_owned());
+}
+
+/// Returns a handle to the process registered under `name`, if any.
+///
+/// Fails with [`JobError::WrongType`] if the name is bound to a different type of handle.
+pub fn lookup<H: Registrable<Handle = H>>(name: &str) -> Result<Option<H>, JobError> {
+    let Some(entry) = request(|registry| registry.with_timeout(REQUEST_TIMEOUT).resolve(name.to_owned())) else {
+        return Ok(None);
+    };
+
+    if entry.handle_type != std::any::type_name::<H>() {
+        return Err(JobError::WrongType(name.to_owned()));
+    }
+
+    Ok(Some(H::from_process(entry.node, entry.process)))
+}
+
+/// Returns the registered names, in order.
+pub fn names() -> Vec<String> {
+    request(|registry| registry.with_timeout(REQUEST_TIMEOUT).names())
+}
+
+/// Sends `request` to the registry, looking the registry up again whenever it does not answer.
+fn request<R>(request: impl Fn(ProcessRef<Registry>) -> Result<R, Timeout>) -> R {
+    singleton::request::<Registry, R>(NAME, (), LOOKUP_TIMEOUT, request)
+}
+
+fn registry() -> ProcessRef<Registry> {
+    loop {
+        if let Some(registry) = singleton::cluster_wide::<Registry>(NAME, (), LOOKUP_TIMEOUT) {
+            break registry;
+        }
+    }
+}
+
+impl<T> Registrable for ProcessRef<T>
+where
+    T: AbstractProcess,
+{
+    type Handle = Self;
+
+    fn process(&self) -> (NodeId, u64) {
+        (self.node_id(), self.id())
+    }
+
+    fn from_process(node: NodeId, process: u64) -> Self {
+        unsafe { ProcessRef::new(node, process) }
+    }
+}
@@ -0,0 +1,297 @@
+//! Sharded actors, addressed by key across the cluster (i.e., virtual actors).
+//!
+//! A [`Sharded`] set of actors has (at most) one activation per key, on the node that owns the key on a consistent
+//! [`HashRing`] of the nodes that satisfy the actor's placement requirements.  Activations are lazy: the first call for a key
+//! spawns the actor on its owner, and later calls are routed to the same activation.
+//!
+//! Each node runs one shard host, started the first time a key it owns is activated, which keeps track of the activations on
+//! the node.  When the ring changes, the host stops the activations whose key moved to another node, and when a node leaves
+//! the cluster, its keys move to the remaining nodes.  Either way, the key is re-activated on its new owner on the next call,
+//! so the state of a moved activation is lost.
+
+use core::time::Duration;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    time::Instant,
+};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, serializer::Bincode, Process};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::Actor,
+    call,
+    cluster::{self, Cluster, NodeId, NodeInfo},
+    singleton, supervision, CallOptions, JobError,
+};
+
+const NAME: &str = "lucidity::sharding";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_secs(1);
+
+/// The amount of time after which an activation that was claimed, but never reported, may be claimed again.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of points that each node has on the ring.
+const VIRTUAL_NODES: u64 = 64;
+
+/// A consistent hash ring over a set of nodes.
+///
+/// Every node has [`VIRTUAL_NODES`] points on the ring, and a key is owned by the node of the first point at, or after, the
+/// hash of the key.  When a node is added, or removed, only the keys next to its points change owners.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HashRing {
+    points: Vec<(u64, NodeId)>,
+}
+
+impl HashRing {
+    /// Creates a ring over `nodes`.
+    pub fn new(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        let mut points = nodes.into_iter().flat_map(|node| (0..VIRTUAL_NODES).map(move |v| (hash(&(node, v)), node))).collect::<Vec<_>>();
+        points.sort_unstable();
+        points.dedup();
+
+        Self { points }
+    }
+
+    /// Returns the node that owns `key`, if the ring has any node.
+    pub fn owner<K: Hash + ?Sized>(&self, key: &K) -> Option<NodeId> {
+        self.owner_of_hash(hash(key))
+    }
+
+    fn owner_of_hash(&self, hash: u64) -> Option<NodeId> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let k = self.points.partition_point(|(point, _)| *point < hash);
+
+        Some(self.points[k % self.points.len()].1)
+    }
+}
+
+/// Hashes `value` the same way on every node (of the same build).
+fn hash<K: Hash + ?Sized>(value: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Returns the ring of the joined nodes (including the local node) that have every label in `requires`.
+fn ring(requires: &[(String, String)]) -> HashRing {
+    let nodes = Cluster::get().nodes().into_iter().chain(std::iter::once(NodeInfo::local()));
+
+    HashRing::new(nodes.filter(|n| n.satisfies(requires)).map(|n| n.id))
+}
+
+/// The answer of a shard host to a claim on a key.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum Claim {
+    /// The key is active in the process with this id.
+    Active(u64),
+    /// Another caller is activating the key.
+    Pending,
+    /// The caller must activate the key, and report the process with `activated` (or `abandoned`, if that fails).
+    Activate,
+}
+
+enum Activation {
+    Starting(Instant),
+    Running(u64),
+}
+
+struct Entry {
+    activation: Activation,
+    hash: u64,
+    requires: Vec<(String, String)>,
+}
+
+/// The shard host process.  There is one per node.
+pub(crate) struct ShardHost {
+    this: ProcessRef<ShardHost>,
+    entries: HashMap<String, Entry>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl ShardHost {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.with_delay(TICK).tick();
+
+        Ok(Self { this, entries: HashMap::new() })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        let mut rings = HashMap::new();
+
+        self.entries.retain(|_, entry| {
+            let Activation::Running(process) = entry.activation else {
+                return true;
+            };
+
+            if !is_alive(process) {
+                return false;
+            }
+
+            // Hand the key off, once it moved to another node.
+            let ring = rings.entry(entry.requires.clone()).or_insert_with(|| ring(&entry.requires));
+            if let Some(owner) = ring.owner_of_hash(entry.hash) {
+                if owner != host::node_id() {
+                    supervision::kill(host::node_id(), process);
+                    return false;
+                }
+            }
+
+            true
+        });
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_request]
+    fn claim(&mut self, name: String, hash: u64, requires: Vec<(String, String)>) -> Claim {
+        let now = Instant::now();
+
+        if let Some(entry) = self.entries.get(&name) {
+            match entry.activation {
+                Activation::Running(process) if is_alive(process) => return Claim::Active(process),
+                Activation::Starting(claimed) if now.duration_since(claimed) < CLAIM_TIMEOUT => return Claim::Pending,
+                _ => {}
+            }
+        }
+
+        self.entries.insert(
+            name,
+            Entry {
+                activation: Activation::Starting(now),
+                hash,
+                requires,
+            },
+        );
+
+        Claim::Activate
+    }
+
+    #[handle_message]
+    fn activated(&mut self, name: String, process: u64) {
+        if let Some(entry) = self.entries.get_mut(&name) {
+            entry.activation = Activation::Running(process);
+        }
+    }
+
+    #[handle_message]
+    fn abandoned(&mut self, name: String) {
+        self.entries.remove(&name);
+    }
+
+    #[handle_request]
+    fn activations(&self) -> usize {
+        self.entries.values().filter(|e| matches!(e.activation, Activation::Running(_))).count()
+    }
+}
+
+fn is_alive(process: u64) -> bool {
+    unsafe { Process::<(), Bincode>::new(host::node_id(), process) }.is_alive()
+}
+
+/// Returns the number of activations on the local node.
+pub fn local_activations() -> usize {
+    match singleton::on_node::<ShardHost>(host::node_id(), NAME, (), LOOKUP_TIMEOUT) {
+        Some(shard_host) => shard_host.activations(),
+        None => 0,
+    }
+}
+
+/// A set of actors `A`, with one activation per key (the argument of the actor's `#[init]` method).
+///
+/// Keys must hash the same way on every node, which holds for the standard types (e.g., `String`, or `u64`) within one build
+/// of the application.  Their `Debug` representation identifies the activation, so it must be unique per key too.
+pub struct Sharded<A> {
+    name: String,
+    options: CallOptions,
+    actor: PhantomData<fn() -> A>,
+}
+
+impl<A> Sharded<A>
+where
+    A: Actor,
+    A::Args: Hash + Debug,
+{
+    /// Creates the set of actors `name`.  Sets with different names have separate activations of the same keys.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: format!("{}::{}", A::name(), name),
+            options: A::options(),
+            actor: PhantomData,
+        }
+    }
+
+    /// Sets the amount of time after which an activation fails with [`JobError::Timeout`], which is also the timeout of the requests
+    /// on the returned handles.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the node that currently owns `key`, if any node satisfies the placement requirements of the actor.
+    pub fn owner(&self, key: &A::Args) -> Option<NodeId> {
+        ring(&self.options.requires).owner(key)
+    }
+
+    /// Returns a handle to the activation of `key`, activating it on its owner if needed.
+    ///
+    /// Fails with [`JobError::NoEligibleNode`] if no node satisfies the placement requirements of the actor, or with
+    /// [`JobError::NodeUnavailable`] if the owner of the key does not answer.
+    pub fn get(&self, key: &A::Args) -> Result<A::Ref, JobError> {
+        let deadline = self.options.deadline();
+        let name = format!("{}::{:?}", self.name, key);
+        let hash = hash(key);
+
+        loop {
+            if call::expired(deadline) {
+                return Err(JobError::Timeout);
+            }
+
+            let owner = ring(&self.options.requires).owner_of_hash(hash).ok_or(JobError::NoEligibleNode)?;
+            let claim = singleton::on_node::<ShardHost>(owner, NAME, (), LOOKUP_TIMEOUT)
+                .map(|shard_host| (shard_host, shard_host.with_timeout(LOOKUP_TIMEOUT).claim(name.clone(), hash, self.options.requires.clone())));
+
+            match claim {
+                Some((_, Ok(Claim::Active(process)))) => {
+                    let process = unsafe { ProcessRef::new(owner, process) };
+                    return Ok(A::handle(process, &self.options));
+                }
+                Some((_, Ok(Claim::Pending))) => lunatic::sleep(self.options.init_retry_interval),
+                Some((shard_host, Ok(Claim::Activate))) => {
+                    match call::start_with::<A::Process>(owner, A::name(), A::limits(), &self.options, deadline, |permit, lease| (permit, lease, key.clone())) {
+                        Ok(process) => {
+                            shard_host.activated(name, process.id());
+                            return Ok(A::handle(process, &self.options));
+                        }
+                        Err(e) => {
+                            shard_host.abandoned(name.clone());
+
+                            // The owner left the cluster, so the key moves to another node.
+                            if e != JobError::UnknownNode(owner) {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    // The owner left the cluster, so the key moves to another node.
+                    if cluster::is_connected(owner) {
+                        cluster::report_failure(owner);
+                        return Err(JobError::NodeUnavailable(owner));
+                    }
+                }
+            }
+        }
+    }
+}
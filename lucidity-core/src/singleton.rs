@@ -0,0 +1,144 @@
+//! Helpers for the well-known, named processes that `lucidity` runs on each node.
+
+use core::time::Duration;
+
+use lunatic::{
+    ap::{ProcessRef, StartupError},
+    host,
+    serializer::Bincode,
+    time::Timeout,
+    AbstractProcess, Mailbox, Process, ProcessConfig, Tag,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Returns the process registered as `name` on `node`, starting it with `arg` if it is not running yet.
+///
+/// When `node` is not the local node, a small bootstrap process is spawned on `node` to perform
+/// the lookup (lunatic registries are node local), and the reference is sent back.  If the bootstrap
+/// does not answer within `timeout`, `None` is returned.
+pub(crate) fn on_node<T>(node: u64, name: &str, arg: T::Arg, timeout: Duration) -> Option<ProcessRef<T>>
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Serialize + DeserializeOwned,
+{
+    if node == host::node_id() {
+        return Some(get_or_start::<T>(name, arg));
+    }
+
+    let tag = Tag::new();
+    let this = unsafe { Process::<ProcessRef<T>, Bincode>::this() };
+    let _ = Process::<(), Bincode>::spawn_node(node, (this, tag, name.to_owned(), arg), bootstrap::<T>);
+
+    let mailbox = unsafe { Mailbox::<ProcessRef<T>, Bincode>::new() };
+    mailbox.tag_receive_timeout(&[tag], timeout).ok()
+}
+
+/// Returns the process registered as `name` on the anchor node of the cluster, starting it if needed.
+///
+/// See [`anchor_node`] for how the anchor node is chosen.  The reference is cached on the local node, until the anchor
+/// node changes, or the cached process is known to be gone.
+pub(crate) fn cluster_wide<T>(name: &str, arg: T::Arg, timeout: Duration) -> Option<ProcessRef<T>>
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Serialize + DeserializeOwned,
+{
+    // Remember the reference locally, so that only the first lookup on each node crosses the network.
+    if let Some(process) = ProcessRef::<T>::lookup(&anchor_name(name)) {
+        let alive = process.node_id() != host::node_id() || process.is_alive();
+        if alive && process.node_id() == anchor_node() {
+            return Some(process);
+        }
+    }
+
+    resolve::<T>(name, arg, timeout)
+}
+
+/// Sends `request` to the cluster-wide process `name` (see [`cluster_wide`]), and returns its answer.
+///
+/// A request that is not answered (i.e., that times out) may have been sent to a coordinator that died, or whose node
+/// left the cluster, so the process is resolved again on the anchor node, bypassing the cached reference, and the
+/// request is retried.  The request must therefore be safe to retry.
+pub(crate) fn request<T, R>(name: &str, arg: T::Arg, timeout: Duration, request: impl Fn(ProcessRef<T>) -> Result<R, Timeout>) -> R
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Clone + Serialize + DeserializeOwned,
+{
+    let mut process = cluster_wide::<T>(name, arg.clone(), timeout);
+
+    loop {
+        if let Some(answer) = process.and_then(|p| request(p).ok()) {
+            return answer;
+        }
+
+        process = resolve::<T>(name, arg.clone(), timeout);
+    }
+}
+
+/// The node that hosts cluster-wide coordinator processes.
+///
+/// This is the lowest node id that the local node knows about, which is usually the node that
+/// started the application (it connects to the control node first).
+///
+/// The anchor is a single point of failure: the state of every coordinator lives on it, and is lost when the
+/// anchor node leaves (the coordinators start again, empty, on the next anchor).  Each node also picks the anchor
+/// from its own view of the cluster, so while those views differ (e.g., while a node joins or leaves), nodes may
+/// briefly talk to different coordinators.
+pub(crate) fn anchor_node() -> u64 {
+    lunatic::distributed::nodes().into_iter().chain(std::iter::once(host::node_id())).min().unwrap()
+}
+
+fn anchor_name(name: &str) -> String {
+    format!("{}@anchor", name)
+}
+
+/// Like [`cluster_wide`], but bypasses (and replaces) the cached reference, e.g., after a request to it timed out.
+pub(crate) fn resolve<T>(name: &str, arg: T::Arg, timeout: Duration) -> Option<ProcessRef<T>>
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Serialize + DeserializeOwned,
+{
+    let process = on_node::<T>(anchor_node(), name, arg, timeout)?;
+    process.register(&anchor_name(name));
+
+    Some(process)
+}
+
+fn get_or_start<T>(name: &str, arg: T::Arg) -> ProcessRef<T>
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Serialize + DeserializeOwned,
+{
+    let registered = ProcessRef::<T>::lookup(name);
+    if let Some(process) = registered {
+        if process.is_alive() {
+            return process;
+        }
+    }
+
+    // These processes outlive the job that started them, so they must not inherit its fuel or memory limits.
+    let mut config = ProcessConfig::new().unwrap();
+    config.set_can_spawn_processes(true);
+
+    // The name of a process that died stays registered, so its replacement is registered over it.
+    let started = match registered {
+        Some(_) => T::configure(&config).start(arg).map(|process| {
+            process.register(&name);
+            process
+        }),
+        None => T::configure(&config).start_as(&name, arg),
+    };
+
+    match started {
+        Ok(process) => process,
+        Err(StartupError::NameAlreadyRegistered(process)) => process,
+        Err(e) => panic!("Failed to start `{}`: {:#?}", name, e),
+    }
+}
+
+fn bootstrap<T>((parent, tag, name, arg): (Process<ProcessRef<T>, Bincode>, Tag, String, T::Arg), _: Mailbox<(), Bincode>)
+where
+    T: AbstractProcess<Serializer = Bincode>,
+    T::Arg: Serialize + DeserializeOwned,
+{
+    parent.tag_send(tag, get_or_start::<T>(&name, arg));
+}
@@ -0,0 +1,195 @@
+//! Supervision of the worker processes that run jobs.
+//!
+//! The service process of a job is linked to its worker.  When the worker dies before delivering a
+//! result, the service consults its [`Restarts`] to decide whether (and where) to spawn a new worker,
+//! so that the caller's handle to the service stays valid across restarts.
+//!
+//! Lunatic can only link node local processes, so a worker spawned on another node is represented locally
+//! by a small runner process (see [`link_remote`]), which fails whenever the remote worker fails.
+
+use core::time::Duration;
+use std::time::Instant;
+
+use lunatic::{host, serializer::Bincode, Mailbox, Process, Tag};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{call, cluster, watch, CallOptions};
+
+/// The interval at which a runner checks that the node of its remote worker is still connected.
+const NODE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the worker of a job is restarted, like the restart types of an OTP supervisor.
+///
+/// Where a restarted worker runs is set separately, with [`RestartNode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Restart {
+    /// The worker is never restarted, and the job fails with [`crate::JobError::Failed`] once the worker dies without a result.
+    #[default]
+    Never,
+    /// The worker is restarted only when it exits abnormally (i.e., it panics, is killed, runs out of fuel, or its node leaves
+    /// the cluster) before delivering its result.
+    Transient,
+    /// The worker is restarted whenever it exits before delivering its result.
+    ///
+    /// The worker of a job only exits normally once it delivered its result, so for jobs, this is the same as [`Restart::Transient`].
+    Permanent,
+}
+
+/// Where the worker of a job is restarted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartNode {
+    /// The worker is restarted on the node it failed on, or on another eligible node if that node left the cluster.
+    #[default]
+    Same,
+    /// The worker is restarted on another eligible node than the one it failed on (when there is one).
+    Different,
+    /// The worker is only ever restarted on the node it failed on, and the job fails once that node left the cluster.
+    ///
+    /// This is always the case for the calls that target a node.
+    Pinned,
+}
+
+/// The restart history of one job.
+///
+/// Restarts are allowed as long as there were fewer than `max_restarts` of them within the last `restart_period`
+/// (the restart intensity).
+#[derive(Clone, Debug)]
+pub struct Restarts {
+    policy: Restart,
+    node: RestartNode,
+    max_restarts: u32,
+    period: Duration,
+    requires: Vec<(String, String)>,
+    history: Vec<Instant>,
+    total: u32,
+}
+
+impl Restarts {
+    /// Creates an empty restart history with the restart options of `options`.
+    pub fn new(options: &CallOptions) -> Self {
+        Self {
+            policy: options.restart,
+            node: if options.node.is_some() { RestartNode::Pinned } else { options.restart_node },
+            max_restarts: options.max_restarts,
+            period: options.restart_period,
+            requires: options.requires.clone(),
+            history: Vec::new(),
+            total: 0,
+        }
+    }
+
+    /// Records the abnormal exit of the worker that ran on `failed_node`, and returns the node to restart it on, if it may be restarted.
+    pub fn on_failure(&mut self, failed_node: u64) -> Option<u64> {
+        if self.policy == Restart::Never {
+            return None;
+        }
+
+        // A worker can not be restarted on a node that left the cluster.
+        let connected = cluster::is_connected(failed_node);
+        if !connected && self.node == RestartNode::Pinned {
+            return None;
+        }
+
+        let now = Instant::now();
+        self.history.retain(|t| now.duration_since(*t) < self.period);
+
+        if self.history.len() >= self.max_restarts as usize {
+            return None;
+        }
+
+        self.history.push(now);
+        self.total += 1;
+
+        match self.node {
+            RestartNode::Same | RestartNode::Pinned if connected => Some(failed_node),
+            _ => {
+                let nodes = call::eligible_nodes(&self.requires)
+                    .into_iter()
+                    .chain(std::iter::once(host::node_id()))
+                    .filter(|n| *n != failed_node)
+                    .collect::<Vec<_>>();
+
+                Some(nodes.choose(&mut rand::thread_rng()).copied().unwrap_or(failed_node))
+            }
+        }
+    }
+
+    /// Returns the number of restarts so far.
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+}
+
+/// A message to the runner of a remote worker.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum Report {
+    Started(u64),
+    Done,
+    Exited,
+}
+
+/// The local end of a worker running on another node.
+///
+/// The worker must call [`RemoteLink::done`] once it has delivered its result; if it exits without doing so (or its
+/// node leaves the cluster), the runner dies, which triggers the link death handler of the process that called [`link_remote`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteLink {
+    runner: Process<Report, Bincode>,
+}
+
+impl RemoteLink {
+    /// Starts watching the worker process `process_id` on the node given to [`link_remote`].
+    pub fn started(&self, process_id: u64) {
+        self.runner.send(Report::Started(process_id));
+    }
+
+    /// Notifies the runner that the worker delivered its result.
+    pub fn done(&self) {
+        self.runner.send(Report::Done);
+    }
+}
+
+impl watch::OnExit for RemoteLink {
+    fn on_exit(self) {
+        self.runner.send(Report::Exited);
+    }
+}
+
+/// Kills the process `process_id` on `node`.
+pub fn kill(node: u64, process_id: u64) {
+    if node == host::node_id() {
+        unsafe { Process::<(), Bincode>::new(node, process_id) }.kill();
+    } else {
+        let _ = Process::<(), Bincode>::spawn_node(node, process_id, killer);
+    }
+}
+
+fn killer(process_id: u64, _: Mailbox<(), Bincode>) {
+    let target = unsafe { Process::<(), Bincode>::new(host::node_id(), process_id) };
+    target.kill();
+}
+
+/// Spawns a runner for a worker on `node`, linked to the calling process with `tag`.
+pub fn link_remote(node: u64, tag: Tag) -> RemoteLink {
+    let runner = Process::<Report, Bincode>::spawn_link_tag(node, tag, runner);
+
+    RemoteLink { runner }
+}
+
+fn runner(node: u64, mailbox: Mailbox<Report, Bincode>) {
+    let this = RemoteLink { runner: mailbox.this() };
+
+    loop {
+        match mailbox.receive_timeout(NODE_CHECK_INTERVAL) {
+            Ok(Report::Started(process_id)) => watch::on_exit(node, process_id, this.clone()),
+            Ok(Report::Done) => return,
+            Ok(Report::Exited) => panic!("The worker on node {} exited without a result.", node),
+            Err(_) => {
+                if !lunatic::distributed::nodes().contains(&node) {
+                    panic!("The node {} of the worker left the cluster.", node);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Cluster-wide synchronization primitives.
+//!
+//! The primitives are named, and backed by a single coordinator process, running on the anchor node of the cluster, so
+//! values with the same name refer to the same lock (or barrier, or latch) on every node.  Waiting polls the coordinator.
+//!
+//! Locks are leased to the process that acquired them.  A lease is released when its guard is dropped, when its process
+//! exits, or when the node of its process leaves the cluster.  Every grant of a lock carries a fencing token, which grows
+//! with every grant, so that a shared resource can reject the writes of a holder whose lease was since released.
+
+use core::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use lunatic::{abstract_process, ap::Config, ap::ProcessRef, host, time::Timeout, Tag};
+use serde::{Deserialize, Serialize};
+
+use crate::{call, cluster, cluster::NodeId, singleton, watch, JobError};
+
+const NAME: &str = "lucidity::sync";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const TICK: Duration = Duration::from_secs(1);
+
+/// The identity of a lease, of an arrival at a barrier, or of a count down of a latch, unique across the cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct HolderId {
+    node: NodeId,
+    process: u64,
+    tag: i64,
+}
+
+impl HolderId {
+    fn new() -> Self {
+        Self {
+            node: host::node_id(),
+            process: host::process_id(),
+            tag: Tag::new().id(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LockState {
+    holders: HashMap<HolderId, u64>,
+    fence: u64,
+}
+
+#[derive(Default)]
+struct BarrierState {
+    generation: u64,
+    arrived: HashSet<HolderId>,
+}
+
+struct LatchState {
+    remaining: usize,
+    counted: HashSet<HolderId>,
+}
+
+/// The coordinator process.  There is one per cluster, started the first time a primitive is used.
+pub(crate) struct Coordinator {
+    this: ProcessRef<Coordinator>,
+    locks: HashMap<String, LockState>,
+    barriers: HashMap<String, BarrierState>,
+    latches: HashMap<String, LatchState>,
+    watched: HashSet<(NodeId, u64)>,
+}
+
+#[abstract_process(visibility = pub(crate), serializer = lunatic::serializer::Bincode)]
+impl Coordinator {
+    #[init]
+    fn init(config: Config<Self>, _: ()) -> Result<Self, ()> {
+        let this = config.self_ref();
+        this.with_delay(TICK).tick();
+
+        Ok(Self {
+            this,
+            locks: HashMap::new(),
+            barriers: HashMap::new(),
+            latches: HashMap::new(),
+            watched: HashSet::new(),
+        })
+    }
+
+    #[handle_message]
+    fn tick(&mut self) {
+        // The sentinels of the processes on a node that left the cluster are gone with it.
+        for lock in self.locks.values_mut() {
+            lock.holders.retain(|id, _| cluster::is_connected(id.node));
+        }
+        self.watched.retain(|(node, _)| cluster::is_connected(*node));
+
+        self.this.with_delay(TICK).tick();
+    }
+
+    #[handle_request]
+    fn try_acquire(&mut self, name: String, permits: usize, id: HolderId) -> Option<u64> {
+        // The node of the holder may have left the cluster since the request was sent, and watching a process on a node that is
+        // gone would take the coordinator down, so the leases of the holder are released instead.
+        if !cluster::is_connected(id.node) {
+            self.exited(id.node, id.process);
+            return None;
+        }
+
+        let lock = self.locks.entry(name).or_default();
+
+        // A retried request may ask about a lease that was already granted.
+        if let Some(token) = lock.holders.get(&id) {
+            return Some(*token);
+        }
+
+        if lock.holders.len() >= permits {
+            return None;
+        }
+
+        // Fencing tokens are never reused, so the state of a lock is kept once it is released.
+        lock.fence += 1;
+        lock.holders.insert(id, lock.fence);
+
+        // Watch each holding process once, rather than once per lease.
+        if self.watched.insert((id.node, id.process)) {
+            watch::on_exit(
+                id.node,
+                id.process,
+                ReleaseAll {
+                    coordinator: self.this,
+                    node: id.node,
+                    process: id.process,
+                },
+            );
+        }
+
+        Some(lock.fence)
+    }
+
+    #[handle_message]
+    fn release(&mut self, name: String, id: HolderId) {
+        if let Some(lock) = self.locks.get_mut(&name) {
+            lock.holders.remove(&id);
+        }
+    }
+
+    #[handle_message]
+    fn exited(&mut self, node: NodeId, process: u64) {
+        self.watched.remove(&(node, process));
+
+        for lock in self.locks.values_mut() {
+            lock.holders.retain(|id, _| id.node != node || id.process != process);
+        }
+    }
+
+    #[handle_request]
+    fn held(&self, name: String) -> usize {
+        self.locks.get(&name).map(|l| l.holders.len()).unwrap_or_default()
+    }
+
+    #[handle_request]
+    fn arrive(&mut self, name: String, parties: usize, id: HolderId) -> (u64, bool) {
+        let barrier = self.barriers.entry(name).or_default();
+        let generation = barrier.generation;
+
+        barrier.arrived.insert(id);
+        if barrier.arrived.len() < parties {
+            return (generation, false);
+        }
+
+        barrier.arrived.clear();
+        barrier.generation += 1;
+
+        (generation, true)
+    }
+
+    #[handle_request]
+    fn depart(&mut self, name: String, generation: u64, id: HolderId) -> bool {
+        match self.barriers.get_mut(&name) {
+            Some(barrier) if barrier.generation == generation => barrier.arrived.remove(&id),
+            _ => false,
+        }
+    }
+
+    #[handle_request]
+    fn generation(&self, name: String) -> u64 {
+        self.barriers.get(&name).map(|b| b.generation).unwrap_or_default()
+    }
+
+    #[handle_request]
+    fn count_down(&mut self, name: String, count: usize, id: HolderId) {
+        let latch = self.latches.entry(name).or_insert_with(|| LatchState {
+            remaining: count,
+            counted: HashSet::new(),
+        });
+
+        // A retried request must not count down twice.
+        if latch.remaining > 0 && latch.counted.insert(id) {
+            latch.remaining -= 1;
+        }
+    }
+
+    #[handle_request]
+    fn remaining(&self, name: String, count: usize) -> usize {
+        self.latches.get(&name).map(|l| l.remaining).unwrap_or(count)
+    }
+}
+
+/// Releases the leases of a process once it exits.
+#[derive(Serialize, Deserialize)]
+struct ReleaseAll {
+    coordinator: ProcessRef<Coordinator>,
+    node: NodeId,
+    process: u64,
+}
+
+impl watch::OnExit for ReleaseAll {
+    fn on_exit(self) {
+        self.coordinator.exited(self.node, self.process);
+    }
+}
+
+/// A lease on a lock, released when it is dropped.
+struct Lease {
+    coordinator: ProcessRef<Coordinator>,
+    name: String,
+    id: HolderId,
+    token: u64,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.coordinator.release(self.name.clone(), self.id);
+    }
+}
+
+/// Waits until one of the `permits` of the lock `name` is free, until `deadline` (if any) passes.
+fn acquire(name: &str, permits: usize, deadline: Option<Instant>) -> Result<Lease, JobError> {
+    let mut coordinator = coordinator();
+    let id = HolderId::new();
+
+    loop {
+        match coordinator.with_timeout(REQUEST_TIMEOUT).try_acquire(name.to_owned(), permits, id) {
+            Ok(Some(token)) => {
+                return Ok(Lease {
+                    coordinator,
+                    name: name.to_owned(),
+                    id,
+                    token,
+                })
+            }
+            Ok(None) => {}
+            // The coordinator may have died, or the anchor node may have left the cluster.
+            Err(_) => coordinator = singleton::resolve::<Coordinator>(NAME, (), LOOKUP_TIMEOUT).unwrap_or(coordinator),
+        }
+
+        if call::expired(deadline) {
+            // The request may have been granted after it timed out.
+            coordinator.release(name.to_owned(), id);
+            return Err(JobError::Timeout);
+        }
+
+        lunatic::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// A cluster-wide mutual exclusion lock.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DistMutex {
+    name: String,
+}
+
+impl DistMutex {
+    /// Creates a handle to the mutex `name`.
+    pub fn new(name: &str) -> Self {
+        Self { name: format!("mutex::{}", name) }
+    }
+
+    /// Blocks until the mutex is acquired.
+    pub fn lock(&self) -> MutexGuard {
+        MutexGuard(acquire(&self.name, 1, None).unwrap())
+    }
+
+    /// Acquires the mutex, if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard> {
+        acquire(&self.name, 1, Some(Instant::now())).ok().map(MutexGuard)
+    }
+
+    /// Blocks until the mutex is acquired, or fails with [`JobError::Timeout`] once `timeout` passes.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<MutexGuard, JobError> {
+        acquire(&self.name, 1, Some(Instant::now() + timeout)).map(MutexGuard)
+    }
+
+    /// Returns whether the mutex is currently held.
+    pub fn is_locked(&self) -> bool {
+        request(|c| c.with_timeout(REQUEST_TIMEOUT).held(self.name.clone())) > 0
+    }
+}
+
+/// A held [`DistMutex`].  The mutex is released when the guard is dropped, or when the process that holds it exits.
+pub struct MutexGuard(Lease);
+
+impl MutexGuard {
+    /// Returns the fencing token of this grant of the mutex.
+    pub fn token(&self) -> u64 {
+        self.0.token
+    }
+}
+
+/// A cluster-wide counting semaphore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DistSemaphore {
+    name: String,
+    permits: usize,
+}
+
+impl DistSemaphore {
+    /// Creates a handle to the semaphore `name`, with `permits` permits.
+    ///
+    /// Every handle to the same semaphore should use the same number of permits.
+    pub fn new(name: &str, permits: usize) -> Self {
+        Self {
+            name: format!("semaphore::{}", name),
+            permits,
+        }
+    }
+
+    /// Blocks until a permit is acquired.
+    pub fn acquire(&self) -> SemaphorePermit {
+        SemaphorePermit(acquire(&self.name, self.permits, None).unwrap())
+    }
+
+    /// Acquires a permit, if one is free.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        acquire(&self.name, self.permits, Some(Instant::now())).ok().map(SemaphorePermit)
+    }
+
+    /// Blocks until a permit is acquired, or fails with [`JobError::Timeout`] once `timeout` passes.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<SemaphorePermit, JobError> {
+        acquire(&self.name, self.permits, Some(Instant::now() + timeout)).map(SemaphorePermit)
+    }
+
+    /// Returns the number of free permits.
+    pub fn available(&self) -> usize {
+        self.permits.saturating_sub(request(|c| c.with_timeout(REQUEST_TIMEOUT).held(self.name.clone())))
+    }
+}
+
+/// A permit of a [`DistSemaphore`].  The permit is returned when it is dropped, or when the process that holds it exits.
+pub struct SemaphorePermit(Lease);
+
+impl SemaphorePermit {
+    /// Returns the fencing token of this grant of the semaphore.
+    pub fn token(&self) -> u64 {
+        self.0.token
+    }
+}
+
+/// A cluster-wide barrier, which releases its waiters once `parties` of them arrived.
+///
+/// The barrier can be reused: the arrivals after a release wait for the next `parties`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Barrier {
+    name: String,
+    parties: usize,
+}
+
+impl Barrier {
+    /// Creates a handle to the barrier `name`, for `parties` parties.
+    pub fn new(name: &str, parties: usize) -> Self {
+        Self { name: name.to_owned(), parties }
+    }
+
+    /// Blocks until `parties` processes wait on the barrier.
+    ///
+    /// Returns `true` for the one process whose arrival released the barrier.
+    pub fn wait(&self) -> bool {
+        self.wait_until(None).unwrap()
+    }
+
+    /// Blocks until `parties` processes wait on the barrier, or fails with [`JobError::Timeout`] once `timeout` passes.
+    ///
+    /// A process that times out no longer counts towards the release of the barrier.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool, JobError> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<bool, JobError> {
+        let id = HolderId::new();
+
+        let (generation, leader) = request(|c| c.with_timeout(REQUEST_TIMEOUT).arrive(self.name.clone(), self.parties, id));
+        if leader {
+            return Ok(true);
+        }
+
+        while request(|c| c.with_timeout(REQUEST_TIMEOUT).generation(self.name.clone())) == generation {
+            if call::expired(deadline) {
+                // The barrier may have been released in the meantime.
+                if request(|c| c.with_timeout(REQUEST_TIMEOUT).depart(self.name.clone(), generation, id)) {
+                    return Err(JobError::Timeout);
+                }
+
+                break;
+            }
+
+            lunatic::sleep(RETRY_INTERVAL);
+        }
+
+        Ok(false)
+    }
+}
+
+/// A cluster-wide countdown latch, which opens once it was counted down `count` times, and then stays open.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Latch {
+    name: String,
+    count: usize,
+}
+
+impl Latch {
+    /// Creates a handle to the latch `name`, which opens after `count` count downs.
+    pub fn new(name: &str, count: usize) -> Self {
+        Self { name: name.to_owned(), count }
+    }
+
+    /// Counts the latch down once.
+    pub fn count_down(&self) {
+        let id = HolderId::new();
+
+        request(|c| c.with_timeout(REQUEST_TIMEOUT).count_down(self.name.clone(), self.count, id));
+    }
+
+    /// Returns the number of count downs left before the latch opens.
+    pub fn count(&self) -> usize {
+        request(|c| c.with_timeout(REQUEST_TIMEOUT).remaining(self.name.clone(), self.count))
+    }
+
+    /// Blocks until the latch opens.
+    pub fn wait(&self) {
+        self.wait_until(None).unwrap()
+    }
+
+    /// Blocks until the latch opens, or fails with [`JobError::Timeout`] once `timeout` passes.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), JobError> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<(), JobError> {
+        while self.count() > 0 {
+            if call::expired(deadline) {
+                return Err(JobError::Timeout);
+            }
+
+            lunatic::sleep(RETRY_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends `request` to the coordinator, looking the coordinator up again whenever it does not answer.
+fn request<R>(request: impl Fn(ProcessRef<Coordinator>) -> Result<R, Timeout>) -> R {
+    singleton::request::<Coordinator, R>(NAME, (), LOOKUP_TIMEOUT, request)
+}
+
+fn coordinator() -> ProcessRef<Coordinator> {
+    loop {
+        if let Some(coordinator) = singleton::cluster_wide::<Coordinator>(NAME, (), LOOKUP_TIMEOUT) {
+            break coordinator;
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Helpers for reacting to the exit of a process, wherever it runs.
+
+use lunatic::{host, serializer::Bincode, Mailbox, MessageSignal, Process, ProcessDiedSignal};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// An action that is run once a watched process exits.
+///
+/// The action is serialized into a small sentinel process on the node of the watched process,
+/// since lunatic can only monitor node local processes.
+pub(crate) trait OnExit: Serialize + DeserializeOwned {
+    /// Runs the action.
+    fn on_exit(self);
+}
+
+/// Runs `action` once the process `process_id` on `node` exits, for any reason.
+pub(crate) fn on_exit<E>(node: u64, process_id: u64, action: E)
+where
+    E: OnExit,
+{
+    if node == host::node_id() {
+        let _ = Process::<(), Bincode>::spawn((process_id, action), sentinel::<E>);
+    } else {
+        let _ = Process::<(), Bincode>::spawn_node(node, (process_id, action), sentinel::<E>);
+    }
+}
+
+fn sentinel<E>((process_id, action): (u64, E), mailbox: Mailbox<(), Bincode>)
+where
+    E: OnExit,
+{
+    let target = unsafe { Process::<(), Bincode>::new(host::node_id(), process_id) };
+
+    let mailbox = mailbox.monitorable();
+    mailbox.monitor(target);
+
+    // Start monitoring before the liveness check, so that an exit in between is not missed.
+    while target.is_alive() {
+        if let MessageSignal::Signal(ProcessDiedSignal(id)) = mailbox.receive() {
+            if id == process_id {
+                break;
+            }
+        }
+    }
+
+    action.on_exit();
+}
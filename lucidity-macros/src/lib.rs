@@ -1,4 +1,4 @@
-//! The `lucidity-macros` crate.  Provides the `job` macro.
+//! The `lucidity-macros` crate.  Provides the `job` and `actor` macros.
 
 #![warn(rustdoc::broken_intra_doc_links, rust_2018_idioms, clippy::all, missing_docs)]
 
@@ -8,7 +8,7 @@ use std::str::FromStr;
 use convert_case::{Case, Casing};
 use proc_macro2::{Literal, TokenStream, TokenTree};
 use quote::quote;
-use syn::{Ident, ItemFn};
+use syn::{Ident, ItemFn, ItemImpl};
 
 /// The `job` macro.
 ///
@@ -89,17 +89,27 @@ fn job_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
         quote! { #(#a),* }
     };
     let arguments_args_tuple_list = quote! { #arguments_args_tuple };
+    let arguments_tuple = if arguments.len() == 1 {
+        quote! { #closure_arguments }
+    } else {
+        quote! { (#closure_arguments) }
+    };
 
     // Names of generated identifiers.
 
     let service_name_ident = Ident::new(&format!("{}Service", name_pascal), name_pascal.span());
     let job_name_ident = Ident::new(&format!("{}Job", name_pascal), name_pascal.span());
+    let call_ident = Ident::new(&format!("{}Call", name_pascal), name_pascal.span());
+    let job_ref_ident = Ident::new(&format!("{}JobRef", name_pascal), name_pascal.span());
 
     let local_fn_ident = Ident::new(&format!("{}_local", name), name.span());
     let remote_fn_ident = Ident::new(&format!("{}_remote", name), name.span());
     let local_async_fn_ident = Ident::new(&format!("{}_local_async", name), name.span());
     let remote_async_fn_ident = Ident::new(&format!("{}_remote_async", name), name.span());
     let remote_fanout_fn_ident = Ident::new(&format!("{}_remote_fanout", name), name.span());
+    let broadcast_fn_ident = Ident::new(&format!("{}_broadcast", name), name.span());
+    let on_fn_ident = Ident::new(&format!("{}_on", name), name.span());
+    let on_async_fn_ident = Ident::new(&format!("{}_on_async", name), name.span());
 
     let get_ident = Ident::new(&format!("{}_get", name), name.span());
     let set_ident = Ident::new(&format!("{}_set", name), name.span());
@@ -108,29 +118,7 @@ fn job_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     // Parse the attributes.
 
-    let attr = attr.into_iter().collect::<Vec<_>>();
-    let attr = attr
-        .split(|t| match t {
-            TokenTree::Punct(punct) => punct.as_char() == ',',
-            _ => false,
-        })
-        .map(|tt| {
-            let tt = tt.iter().collect::<Vec<_>>();
-            tt.split(|t| match t {
-                TokenTree::Punct(punct) => punct.as_char() == '=',
-                _ => false,
-            })
-            .flat_map(ToOwned::to_owned)
-            .collect::<Vec<_>>()
-        })
-        .filter_map(|v| {
-            if v.len() != 2 {
-                return None;
-            }
-
-            Some((v[0], v[1]))
-        })
-        .collect::<Vec<_>>();
+    let attr = parse_attributes(attr);
 
     // Set the attributes.
 
@@ -142,8 +130,19 @@ fn job_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut shutdown_retry_interval_ms = 100;
     let mut memory = 100u64 * 1024 * 1024;
     let mut fuel = 10u64;
+    let mut priority = 0u32;
+    let mut rate_limit = None;
+    let mut max_concurrency = None;
     let mut fanout = Literal::from_str("\"roundrobin\"").unwrap();
-    for (key, value) in attr {
+    let mut restart = Literal::from_str("\"never\"").unwrap();
+    let mut restart_node = Literal::from_str("\"same\"").unwrap();
+    let mut max_restarts = 3u32;
+    let mut restart_period_ms = 5000u64;
+    let mut idempotent = false;
+    let mut requires = Vec::new();
+    let mut speculative = None;
+    let mut speculative_percentile = 90f64;
+    for (key, value) in &attr {
         let key = key.to_string();
         let key = key.as_str();
 
@@ -236,354 +235,838 @@ fn job_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
                     Err(_) => panic!("Invalid attribute argument value `{}`.", value),
                 }
             }
-            "fanout" => {
+            "priority" => {
                 let value = value.to_string();
                 let value = value.as_str();
 
-                fanout = Literal::from_str(value).expect("The fanout was not a valid string.");
+                match value.parse::<u32>() {
+                    Ok(v) => {
+                        priority = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
             }
-            _ => panic!("Invalid attribute argument name `{}`.", key),
-        }
-    }
-
-    // Get some special quotes.
+            "rate_limit" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-    let config = quote! {
-        let mut config = lucidity::lunatic::ProcessConfig::new().unwrap();
-        config.set_can_spawn_processes(true);
-        config.set_can_create_configs(true);
-        config.set_can_compile_modules(true);
-        config.set_max_fuel(#fuel);
-        config.set_max_memory(#memory);
-    };
+                match value.parse::<f64>() {
+                    Ok(v) if v > 0.0 => {
+                        rate_limit = Some(v);
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "max_concurrency" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-    let service = quote! {
-        let service = loop {
-            match #service_name_ident::on_node(node).configure(&config).start_timeout((), std::time::Duration::from_millis(#init_retry_interval_ms)) {
-                Ok(s) => {
-                    break s;
-                },
-                Err(e) => match e {
-                    lucidity::lunatic::ap::StartupError::TimedOut => continue,
-                    _ => panic!("Init error: {:#?}", e)
+                match value.parse::<usize>() {
+                    Ok(v) if v > 0 => {
+                        max_concurrency = Some(v);
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
                 }
             }
-        };
-    };
+            "fanout" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-    // Generate the code.
+                fanout = Literal::from_str(value).expect("The fanout was not a valid string.");
+            }
+            "restart" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-    let gen = quote! {
-        #input
+                match value {
+                    "\"never\"" | "\"transient\"" | "\"permanent\"" => {
+                        restart = Literal::from_str(value).unwrap();
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "restart_node" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-        /// The generated "local" function.
-        ///
-        /// This is a helper function for cases where you want to call the [`lucidity::job`] synchronously
-        /// on the local node.
-        ///
-        /// This function will block the current lunatic process until completion of the service process.
-        ///
-        /// The sync method retry interval is defined by `sync_retry_interval_ms` (default 100ms).
-        ///
-        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
-        ///
-        /// The service process is `shutdown` before completion of this call.
-        #vis fn #local_fn_ident(#arguments) -> #return_type {
-            use lucidity::lunatic::AbstractProcess;
+                match value {
+                    "\"same\"" | "\"different\"" | "\"pinned\"" => {
+                        restart_node = Literal::from_str(value).unwrap();
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "max_restarts" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u32>() {
+                    Ok(v) => {
+                        max_restarts = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "requires" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-            let node = lucidity::lunatic::host::node_id();
+                let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+                    panic!("Invalid attribute argument value `{}`.", value);
+                };
 
-            #config
+                for requirement in inner.split(',') {
+                    match requirement.split_once('=') {
+                        Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                            requires.push((key.trim().to_owned(), value.trim().to_owned()));
+                        }
+                        _ => panic!("Invalid attribute argument value `{}`.", value),
+                    }
+                }
+            }
+            "idempotent" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-            #service
+                match value.parse::<bool>() {
+                    Ok(v) => {
+                        idempotent = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "speculative" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-            // Get the result.
-            let result = loop {
-                if let Ok(r) = service.with_timeout(std::time::Duration::from_millis(#sync_retry_interval_ms)).#get_ident(#call_arguments) {
-                    break r;
+                match value.parse::<f64>() {
+                    Ok(v) if v > 0.0 && v <= 100.0 => {
+                        speculative = Some(v);
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
                 }
-            };
+            }
+            "speculative_percentile" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-            // Shutdown.
-            loop {
-                if let Ok(_) = service.with_timeout(std::time::Duration::from_millis(#shutdown_retry_interval_ms)).shutdown() {
-                    break;
+                match value.parse::<f64>() {
+                    Ok(v) if v > 0.0 && v <= 100.0 => {
+                        speculative_percentile = v;
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
                 }
             }
+            "restart_period_ms" => {
+                let value = value.to_string();
+                let value = value.as_str();
 
-            result
+                match value.parse::<u64>() {
+                    Ok(v) => {
+                        restart_period_ms = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            _ => panic!("Invalid attribute argument name `{}`.", key),
         }
+    }
 
-        /// The generated "remote" function.
-        ///
-        /// This is a helper function for cases where you want to call the [`lucidity::job`] synchronously
-        /// on a random node in the distributed pool.
-        ///
-        /// This function will block the current lunatic process until completion of the service process.
-        ///
-        /// The sync method retry interval is defined by `sync_retry_interval_ms` (default 100ms).
-        ///
-        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
-        ///
-        /// The service process is `shutdown` before completion of this call.
-        #vis fn #remote_fn_ident(#arguments) -> #return_type {
-            use lucidity::lunatic::AbstractProcess;
-            use lucidity::rand::seq::SliceRandom;
+    // Get some special quotes.
 
-            let nodes = lucidity::lunatic::distributed::nodes();
-            let node = *nodes.choose(&mut lucidity::rand::thread_rng()).unwrap();
+    let fanout = if fanout.to_string() == "\"roundrobin\"" {
+        quote! { lucidity::Fanout::RoundRobin }
+    } else {
+        // Default to random.
+        quote! { lucidity::Fanout::Random }
+    };
 
-            #config
+    // Duplicating a call is only safe when running the job twice is harmless.
+    if speculative.is_some() && !idempotent {
+        panic!("The `speculative` attribute argument requires `idempotent = true`.");
+    }
 
-            #service
+    let requires = requires.iter().map(|(key, value)| quote! { (String::from(#key), String::from(#value)) });
 
-            // Get the result.
-            let result = loop {
-                if let Ok(r) = service.with_timeout(std::time::Duration::from_millis(#sync_retry_interval_ms)).#get_ident(#call_arguments) {
-                    break r;
-                }
-            };
+    let speculation = match speculative {
+        Some(quorum) => quote! { Some(lucidity::Speculation { quorum: #quorum, percentile: #speculative_percentile }) },
+        None => quote! { None },
+    };
 
-            // Shutdown.
-            loop {
-                if let Ok(_) = service.with_timeout(std::time::Duration::from_millis(#shutdown_retry_interval_ms)).shutdown() {
-                    break;
-                }
+    let speculative_setter = if idempotent {
+        quote! {
+            /// Sets when the calls of a fanout are duplicated on another node (or disables it with `None`).
+            #vis fn speculative(mut self, speculation: Option<lucidity::Speculation>) -> Self {
+                self.options.speculation = speculation;
+                self
             }
-
-            result
         }
+    } else {
+        quote! {}
+    };
 
-        /// The generated "local async" function.
-        ///
-        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
-        /// on the local node.
-        ///
-        /// This function returns a [`Job`] that can be used to poll, or await, the result of the async process.
-        ///
-        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
-        ///
-        /// The spawned service process is `shutdown` when the returned [`Job`] is dropped.
-        #vis fn #local_async_fn_ident(#arguments) -> #job_name_ident {
-            use lucidity::lunatic::AbstractProcess;
-
-            let node = lucidity::lunatic::host::node_id();
-
-            #config
-
-            #service
+    let restart = match restart.to_string().as_str() {
+        "\"transient\"" => quote! { lucidity::Restart::Transient },
+        "\"permanent\"" => quote! { lucidity::Restart::Permanent },
+        _ => quote! { lucidity::Restart::Never },
+    };
 
-            // Get the result.
-            let _ = loop {
-                if let Ok(r) = service.with_timeout(std::time::Duration::from_millis(#async_init_retry_interval_ms)).#async_init_ident(#call_arguments) {
-                    break r;
-                }
-            };
+    let restart_node = match restart_node.to_string().as_str() {
+        "\"different\"" => quote! { lucidity::RestartNode::Different },
+        "\"pinned\"" => quote! { lucidity::RestartNode::Pinned },
+        _ => quote! { lucidity::RestartNode::Same },
+    };
 
-            #job_name_ident(lucidity::Job {
-                process: service
-            })
+    let default_options = quote! {
+        lucidity::CallOptions {
+            memory: #memory,
+            fuel: #fuel,
+            priority: lucidity::dispatcher::priority_or(#priority),
+            fanout: #fanout,
+            node: None,
+            timeout: None,
+            requires: vec![#(#requires),*],
+            speculation: #speculation,
+            restart: #restart,
+            restart_node: #restart_node,
+            max_restarts: #max_restarts,
+            restart_period: std::time::Duration::from_millis(#restart_period_ms),
+            init_retry_interval: std::time::Duration::from_millis(#init_retry_interval_ms),
+            sync_retry_interval: std::time::Duration::from_millis(#sync_retry_interval_ms),
+            async_init_retry_interval: std::time::Duration::from_millis(#async_init_retry_interval_ms),
+            async_get_retry_interval: std::time::Duration::from_millis(#async_get_retry_interval_ms),
+            async_set_retry_interval: std::time::Duration::from_millis(#async_set_retry_interval_ms),
+            shutdown_retry_interval: std::time::Duration::from_millis(#shutdown_retry_interval_ms),
         }
+    };
 
-        /// The generated "remote async" function.
-        ///
-        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
-        /// on a random node in the distributed pool.
-        ///
-        /// This function returns a [`Job`] that can be used to poll, or await, the result of the async process.
-        ///
-        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
-        ///
-        /// The spawned service process is `shutdown` when the returned [`Job`] is dropped.
-        #vis fn #remote_async_fn_ident(#arguments) -> #job_name_ident {
-            use lucidity::lunatic::AbstractProcess;
-            use lucidity::rand::seq::SliceRandom;
+    let limits = if rate_limit.is_some() || max_concurrency.is_some() {
+        let rate_limit = match rate_limit {
+            Some(r) => quote! { Some(#r) },
+            None => quote! { None },
+        };
+        let max_concurrency = match max_concurrency {
+            Some(m) => quote! { Some(#m) },
+            None => quote! { None },
+        };
 
-            let nodes = lucidity::lunatic::distributed::nodes();
-            let node = *nodes.choose(&mut lucidity::rand::thread_rng()).unwrap();
+        quote! { Some(lucidity::limits::Limits { rate_limit: #rate_limit, max_concurrency: #max_concurrency }) }
+    } else {
+        quote! { None }
+    };
 
-            #config
+    let job_name = quote! { concat!(module_path!(), "::", stringify!(#name)) };
 
-            #service
+    // Generate the code.
 
-            // Get the result.
-            let _ = loop {
-                if let Ok(r) = service.with_timeout(std::time::Duration::from_millis(#async_init_retry_interval_ms)).#async_init_ident(#call_arguments) {
-                    break r;
-                }
-            };
+    let gen = quote! {
+        #input
 
-            #job_name_ident(lucidity::Job {
-                process: service
-            })
+        /// The generated call builder module for the [`lucidity::job`].
+        #vis mod #name {
+            /// Returns a call builder for the [`lucidity::job`], with the values of its attribute as defaults.
+            pub fn call() -> super::#call_ident {
+                super::#call_ident::default()
+            }
         }
 
-        /// The generated "remote async fanout" function.
-        ///
-        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
-        /// across a distributed set of machines.  Essentially, this is like a "rayon fanout", except that
-        /// it fans across machines, rather than cores.
-        ///
-        /// This functionality could be achieved using the other async methods, but this helper makes it easier to
-        /// fanout, wait, and receive all of the results.
+        /// The generated call builder for the [`lucidity::job`].
         ///
-        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
-        ///
-        /// The async get method retry interval is defined by `async_get_retry_interval_ms` (default 100ms).
+        /// This is a helper for cases where you want to override the options of the `lucidity::job` attribute
+        /// (e.g., `memory`, or `fuel`) for a single call.  It is created with `{name}::call()`, and the values of the
+        /// attribute are the defaults.
         ///
-        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
-        #vis fn #remote_fanout_fn_ident(args_list: Vec<#arguments_types_list>) -> Vec<#return_type> {
-            use lucidity::lunatic::AbstractProcess;
-            use lucidity::rand::seq::SliceRandom;
+        /// Unlike the other generated functions, the calls on the builder return a [`lucidity::JobError`] rather than panicking.
+        #[derive(Clone, Debug)]
+        #vis struct #call_ident {
+            options: lucidity::CallOptions,
+        }
 
-            #config
+        impl Default for #call_ident {
+            fn default() -> Self {
+                Self { options: #default_options }
+            }
+        }
 
-            let mut services = Vec::new();
-            let nodes = lucidity::lunatic::distributed::nodes();
+        impl #call_ident {
+            /// Sets the maximum memory of the process running the job.
+            #vis fn memory(mut self, memory: u64) -> Self {
+                self.options.memory = memory;
+                self
+            }
 
-            let random = &mut lucidity::rand::thread_rng();
+            /// Sets the maximum fuel of the process running the job.
+            #vis fn fuel(mut self, fuel: u64) -> Self {
+                self.options.fuel = fuel;
+                self
+            }
 
-            for (k, args) in args_list.into_iter().enumerate() {
-                let node = if #fanout == "roundrobin" {
-                    let num_nodes = nodes.len();
-                    nodes[k % num_nodes]
-                } else {
-                    // Default to random.
-                    *nodes.choose(random).unwrap()
-                };
+            /// Sets the priority of the job on a saturated node.
+            #vis fn priority(mut self, priority: u32) -> Self {
+                self.options.priority = priority;
+                self
+            }
 
-                #service
+            /// Sets the scheme used to place the calls of a fanout.
+            #vis fn fanout(mut self, fanout: lucidity::Fanout) -> Self {
+                self.options.fanout = fanout;
+                self
+            }
 
-                loop {
-                    if let Ok(_) = service.with_timeout(std::time::Duration::from_millis(#async_init_retry_interval_ms)).#async_init_ident(#arguments_args_tuple_list) {
-                        break;
-                    }
-                }
+            /// Sets the node that remote calls run on, rather than a node picked by the placement scheme.
+            #vis fn node(mut self, node: u64) -> Self {
+                self.options.node = Some(node);
+                self
+            }
 
-                services.push(service);
+            /// Sets the amount of time after which the call fails with [`lucidity::JobError::Timeout`].
+            ///
+            /// For the async calls, this only bounds the start of the job.
+            #vis fn timeout(mut self, timeout: std::time::Duration) -> Self {
+                self.options.timeout = Some(timeout);
+                self
             }
 
-            // Get all of the results.
-            let mut results = vec![None; services.len()];
-            loop {
-                let mut done = true;
+            /// Sets the labels that a node must have to run the job, in the form `key=value,key=value` (e.g., `region=ord,size=large`).
+            #vis fn requires(mut self, requirements: &str) -> Self {
+                self.options.requires = lucidity::call::parse_requirements(requirements);
+                self
+            }
 
-                for (k, service) in services.iter_mut().enumerate() {
-                    if results[k].is_some() {
-                        continue;
-                    }
+            #speculative_setter
 
-                    // Get the result.
-                    let result = loop {
-                        if let Ok(r) = service.with_timeout(std::time::Duration::from_millis(#async_get_retry_interval_ms)).#try_get_ident() {
-                            break r;
-                        }
-                    };
+            /// Sets when the worker of the job is restarted after it dies.
+            #vis fn restart(mut self, restart: lucidity::Restart) -> Self {
+                self.options.restart = restart;
+                self
+            }
 
-                    if let Some(result) = result {
-                        // Set the result.
-                        results[k] = Some(result);
+            /// Sets where the worker of the job is restarted.
+            #vis fn restart_node(mut self, restart_node: lucidity::RestartNode) -> Self {
+                self.options.restart_node = restart_node;
+                self
+            }
 
-                        // Shutdown.
-                        loop {
-                            if let Ok(_) = service.with_timeout(std::time::Duration::from_millis(#shutdown_retry_interval_ms)).shutdown() {
-                                break;
-                            }
-                        }
-                    } else {
-                        done = false;
-                    }
-                }
+            /// Sets the maximum number of restarts within the restart period.
+            #vis fn max_restarts(mut self, max_restarts: u32) -> Self {
+                self.options.max_restarts = max_restarts;
+                self
+            }
 
-                if done {
-                    break;
-                }
+            /// Sets the period over which restarts are counted.
+            #vis fn restart_period(mut self, period: std::time::Duration) -> Self {
+                self.options.restart_period = period;
+                self
+            }
 
-                // Give some time for the processes to respond between loops.
-                lucidity::lunatic::sleep(std::time::Duration::from_millis(#async_get_retry_interval_ms));
+            /// Sets the retry interval when starting the service process.
+            #vis fn init_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.init_retry_interval = interval;
+                self
             }
 
-            results.into_iter().map(|result| result.unwrap()).collect()
-        }
+            /// Sets the retry interval when waiting on a blocking result.
+            #vis fn sync_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.sync_retry_interval = interval;
+                self
+            }
 
-        /// The generated [`AbstractProcess`] for the [`lucidity::job`].
-        ///
-        /// This defines the proper methods to achieve synchronous, and asynchronous calls to a process
-        /// that may be local or remote.  All of the generated functions make calls into this processes
-        /// request handlers.
-        #vis struct #service_name_ident(#option_return_type);
+            /// Sets the retry interval when starting the job asynchronously.
+            #vis fn async_init_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.async_init_retry_interval = interval;
+                self
+            }
 
-        #[lucidity::lunatic::abstract_process(serializer = lucidity::lunatic::serializer::Bincode)]
-        impl #service_name_ident {
-            #[init]
-            fn init(_: lucidity::lunatic::ap::Config<Self>, _: ()) -> Result<Self, ()> {
-                Ok(Self(None))
+            /// Sets the retry interval when polling a non-blocking result.
+            #vis fn async_get_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.async_get_retry_interval = interval;
+                self
             }
 
-            #[terminate]
-            fn terminate(self) {
+            /// Sets the retry interval when the job sets its result on the service process.
+            #vis fn async_set_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.async_set_retry_interval = interval;
+                self
+            }
 
+            /// Sets the retry interval when shutting the service process down.
+            #vis fn shutdown_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.shutdown_retry_interval = interval;
+                self
             }
 
-            #[handle_link_death]
-            fn handle_link_death(&self, _tag: lucidity::lunatic::Tag) {
+            /// Calls the [`lucidity::job`] synchronously on the local node, blocking until completion.
+            #vis fn local(&self, #arguments) -> Result<#return_type, lucidity::JobError> {
+                let deadline = self.options.deadline();
+                let service = self.start(self.options.local_node()?, #arguments_tuple, deadline, false)?;
+
+                self.wait(service, self.options.sync_retry_interval, deadline)
             }
 
-            #[handle_request]
-            fn #get_ident(&self, #arguments) -> #return_type {
-                #name(#call_arguments)
+            /// Calls the [`lucidity::job`] synchronously on a remote node, blocking until completion.
+            #vis fn remote(&self, #arguments) -> Result<#return_type, lucidity::JobError> {
+                let deadline = self.options.deadline();
+                let service = self.start(self.options.remote_node()?, #arguments_tuple, deadline, false)?;
+
+                self.wait(service, self.options.sync_retry_interval, deadline)
             }
 
-            #[handle_request]
-            fn #set_ident(&mut self, value: #return_type) {
-                self.0 = Some(value);
+            /// Calls the [`lucidity::job`] asynchronously on the local node, returning a [`Job`] for the result.
+            #vis fn local_async(&self, #arguments) -> Result<#job_name_ident, lucidity::JobError> {
+                let service = self.start(self.options.local_node()?, #arguments_tuple, self.options.deadline(), false)?;
+
+                Ok(#job_name_ident(lucidity::Job { process: service }, self.options.async_get_retry_interval))
             }
 
-            #[handle_request]
-            fn #try_get_ident(&self) -> #option_return_type {
-                self.0.clone()
+            /// Calls the [`lucidity::job`] asynchronously on a remote node, returning a [`Job`] for the result.
+            #vis fn remote_async(&self, #arguments) -> Result<#job_name_ident, lucidity::JobError> {
+                let service = self.start(self.options.remote_node()?, #arguments_tuple, self.options.deadline(), false)?;
+
+                Ok(#job_name_ident(lucidity::Job { process: service }, self.options.async_get_retry_interval))
             }
 
-            #[handle_request]
-            fn #async_init_ident(&self, #arguments) {
-                let parent: lucidity::lunatic::ap::ProcessRef<#service_name_ident> = unsafe { lucidity::lunatic::ap::ProcessRef::new(lucidity::lunatic::host::node_id(), lucidity::lunatic::host::process_id()) };
+            /// Calls the [`lucidity::job`] once per element of `args_list` across the distributed pool, blocking until
+            /// all of the results are in.
+            #vis fn remote_fanout(&self, args_list: Vec<#arguments_types_list>) -> Result<Vec<#return_type>, lucidity::JobError> {
+                let nodes = self.options.fanout_nodes(args_list.len())?;
+
+                self.run(nodes.into_iter().zip(args_list).collect(), false).into_iter().collect()
+            }
 
-                #config
+            /// Calls the [`lucidity::job`] once on every node in the distributed pool (that satisfies the placement requirements
+            /// of the call), blocking until all of the results are in.
+            ///
+            /// Every call is pinned to its node: a call that fails does not affect the others, and its error is reported
+            /// next to its node.
+            #vis fn broadcast(&self, #arguments) -> Vec<(lucidity::cluster::NodeId, Result<#return_type, lucidity::JobError>)> {
+                let nodes = self.options.broadcast_nodes();
+                let args = #arguments_tuple;
 
-                let _ = lucidity::lunatic::Process::spawn_link_config(&config, (parent, #call_arguments), |(parent, #closure_arguments), _: lucidity::lunatic::Mailbox<()>| {
+                // A restarted worker must stay on its node.
+                let mut options = self.options.clone();
+                options.restart_node = lucidity::RestartNode::Pinned;
+                let call = Self { options };
 
-                    let result = #name(#call_arguments);
+                let results = call.run(nodes.iter().map(|node| (*node, args.clone())).collect(), true);
 
-                    loop {
-                        if let Ok(_) = parent.with_timeout(std::time::Duration::from_millis(#async_set_retry_interval_ms)).#set_ident(result.clone()) {
-                            break;
+                nodes.into_iter().zip(results).collect()
+            }
+
+            /// Runs the calls, blocking until all of the results are in.
+            ///
+            /// Pinned calls are never placed elsewhere, or duplicated, and a failed pinned call does not cancel the others.
+            fn run(&self, calls: Vec<(u64, #arguments_types_list)>, pinned: bool) -> Vec<Result<#return_type, lucidity::JobError>> {
+                let deadline = self.options.deadline();
+                let speculation = if pinned { None } else { self.options.speculation };
+
+                // Start all of the services.
+                let mut attempts = Vec::new();
+                let mut results = Vec::new();
+                let mut started = Vec::new();
+                for (node, args) in calls.iter().cloned() {
+                    match self.start(node, args, deadline, pinned) {
+                        Ok(service) => {
+                            attempts.push(vec![(service, service.node_id())]);
+                            results.push(None);
+                            // Calls that waited for a permit only count as running once they started.
+                            started.push(std::time::Instant::now());
+                        }
+                        Err(e) if pinned => {
+                            attempts.push(Vec::new());
+                            results.push(Some(Err(e)));
+                            started.push(std::time::Instant::now());
+                        }
+                        Err(e) => {
+                            for (service, _) in attempts.into_iter().flatten() {
+                                lucidity::call::shutdown(service, &self.options);
+                            }
+
+                            return calls.iter().map(|_| Err(e.clone())).collect();
                         }
                     }
-                });
-            }
-        }
+                }
 
-        /// The [`Job`] type for the generated service.
+                // Get all of the results.
+                let mut speculator = lucidity::call::Speculator::new(started, speculation);
+                loop {
+                    for k in 0..attempts.len() {
+                        let mut a = 0;
+                        while a < attempts[k].len() {
+                            let (process, _) = attempts[k][a];
+
+                            match process.with_timeout(self.options.async_get_retry_interval).#try_get_ident() {
+                                Ok(Ok(Some(result))) => {
+                                    results[k] = Some(Ok(result));
+                                    speculator.finished(k);
+
+                                    // The first result wins, and any duplicate is cancelled.
+                                    for (service, _) in attempts[k].drain(..) {
+                                        lucidity::call::shutdown(service, &self.options);
+                                    }
+                                }
+                                Ok(Err(_)) if attempts[k].len() > 1 => {
+                                    lucidity::call::shutdown(process, &self.options);
+                                    attempts[k].remove(a);
+                                }
+                                Ok(Err(e)) if pinned => {
+                                    results[k] = Some(Err(e));
+
+                                    for (service, _) in attempts[k].drain(..) {
+                                        lucidity::call::shutdown(service, &self.options);
+                                    }
+                                }
+                                Ok(Err(e)) => {
+                                    for (service, _) in attempts.into_iter().flatten() {
+                                        lucidity::call::shutdown(service, &self.options);
+                                    }
+
+                                    return calls.iter().map(|_| Err(e.clone())).collect();
+                                }
+                                _ => a += 1,
+                            }
+                        }
+                    }
+
+                    if attempts.iter().all(Vec::is_empty) {
+                        break;
+                    }
+
+                    if lucidity::call::expired(deadline) {
+                        for (k, attempt) in attempts.iter_mut().enumerate() {
+                            if results[k].is_none() {
+                                results[k] = Some(Err(lucidity::JobError::Timeout));
+                            }
+
+                            for (service, _) in attempt.drain(..) {
+                                lucidity::call::shutdown(service, &self.options);
+                            }
+                        }
+
+                        break;
+                    }
+
+                    // Duplicate the stragglers on another node.
+                    for k in speculator.stragglers() {
+                        let node = self.options.other_node(attempts[k][0].1);
+
+                        if let Ok(service) = self.start(node, calls[k].1.clone(), deadline, false) {
+                            attempts[k].push((service, service.node_id()));
+                        }
+                    }
+
+                    // Give some time for the processes to respond between loops.
+                    lucidity::lunatic::sleep(self.options.async_get_retry_interval);
+                }
+
+                results.into_iter().map(Option::unwrap).collect()
+            }
+
+            #[allow(unused_variables)]
+            fn start(&self, node: u64, args: #arguments_types_list, deadline: Option<std::time::Instant>, pinned: bool) -> Result<lucidity::lunatic::ap::ProcessRef<#service_name_ident>, lucidity::JobError> {
+                // Unless the call is pinned to the node, place it elsewhere when the node is unavailable.
+                let service = lucidity::call::place(node, &self.options, pinned, deadline, |node| lucidity::call::start::<#service_name_ident>(node, #job_name, #limits, &self.options, deadline))?;
+
+                loop {
+                    if lucidity::call::expired(deadline) {
+                        lucidity::call::shutdown(service, &self.options);
+                        return Err(lucidity::JobError::Timeout);
+                    }
+
+                    if let Ok(_) = service.with_timeout(self.options.async_init_retry_interval).#async_init_ident(#arguments_args_tuple_list) {
+                        break;
+                    }
+                }
+
+                Ok(service)
+            }
+
+            fn wait(&self, service: lucidity::lunatic::ap::ProcessRef<#service_name_ident>, interval: std::time::Duration, deadline: Option<std::time::Instant>) -> Result<#return_type, lucidity::JobError> {
+                let result = loop {
+                    match service.with_timeout(interval).#try_get_ident() {
+                        Ok(Ok(Some(r))) => break Ok(r),
+                        Ok(Err(e)) => break Err(e),
+                        _ => {}
+                    }
+
+                    if lucidity::call::expired(deadline) {
+                        break Err(lucidity::JobError::Timeout);
+                    }
+
+                    lucidity::lunatic::sleep(interval);
+                };
+
+                lucidity::call::shutdown(service, &self.options);
+
+                result
+            }
+        }
+
+        /// The generated "local" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] synchronously
+        /// on the local node.
+        ///
+        /// This function will block the current lunatic process until completion of the service process.
+        ///
+        /// The sync method retry interval is defined by `sync_retry_interval_ms` (default 100ms).
+        ///
+        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
+        ///
+        /// The service process is `shutdown` before completion of this call.
+        #vis fn #local_fn_ident(#arguments) -> #return_type {
+            #call_ident::default().local(#closure_arguments).unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        /// The generated "remote" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] synchronously
+        /// on a random node in the distributed pool.
+        ///
+        /// This function will block the current lunatic process until completion of the service process.
+        ///
+        /// The sync method retry interval is defined by `sync_retry_interval_ms` (default 100ms).
+        ///
+        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
+        ///
+        /// The service process is `shutdown` before completion of this call.
+        #vis fn #remote_fn_ident(#arguments) -> #return_type {
+            #call_ident::default().remote(#closure_arguments).unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        /// The generated "local async" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
+        /// on the local node.
+        ///
+        /// This function returns a [`Job`] that can be used to poll, or await, the result of the async process.
+        ///
+        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
+        ///
+        /// The spawned service process is `shutdown` when the returned [`Job`] is dropped.
+        #vis fn #local_async_fn_ident(#arguments) -> #job_name_ident {
+            #call_ident::default().local_async(#closure_arguments).unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        /// The generated "remote async" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
+        /// on a random node in the distributed pool.
+        ///
+        /// This function returns a [`Job`] that can be used to poll, or await, the result of the async process.
+        ///
+        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
+        ///
+        /// The spawned service process is `shutdown` when the returned [`Job`] is dropped.
+        #vis fn #remote_async_fn_ident(#arguments) -> #job_name_ident {
+            #call_ident::default().remote_async(#closure_arguments).unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        /// The generated "remote async fanout" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
+        /// across a distributed set of machines.  Essentially, this is like a "rayon fanout", except that
+        /// it fans across machines, rather than cores.
+        ///
+        /// This functionality could be achieved using the other async methods, but this helper makes it easier to
+        /// fanout, wait, and receive all of the results.
+        ///
+        /// The async init method retry interval is defined by `async_init_retry_interval_ms` (default 100ms).
+        ///
+        /// The async get method retry interval is defined by `async_get_retry_interval_ms` (default 100ms).
+        ///
+        /// The shutdown method retry interval is defined by `shutdown_retry_interval_ms` (default 100ms).
+        #vis fn #remote_fanout_fn_ident(args_list: Vec<#arguments_types_list>) -> Vec<#return_type> {
+            #call_ident::default().remote_fanout(args_list).unwrap_or_else(|e| panic!("{}", e))
+        }
+
+        /// The generated "on" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] synchronously
+        /// on a specific node (which may be the local node).
+        ///
+        /// This function will block the current lunatic process until completion of the service process, and
+        /// fails with [`lucidity::JobError::UnknownNode`] if the node is not (or no longer) connected.
+        #vis fn #on_fn_ident(node: lucidity::cluster::NodeId, #arguments) -> Result<#return_type, lucidity::JobError> {
+            #call_ident::default().node(node).remote(#closure_arguments)
+        }
+
+        /// The generated "on async" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] asynchronously
+        /// on a specific node (which may be the local node).
+        ///
+        /// This function returns a [`Job`] that can be used to poll, or await, the result of the async process, and
+        /// fails with [`lucidity::JobError::UnknownNode`] if the node is not connected.
+        #vis fn #on_async_fn_ident(node: lucidity::cluster::NodeId, #arguments) -> Result<#job_name_ident, lucidity::JobError> {
+            #call_ident::default().node(node).remote_async(#closure_arguments)
+        }
+
+        /// The generated "broadcast" function.
+        ///
+        /// This is a helper function for cases where you want to call the [`lucidity::job`] once on every node
+        /// in the distributed pool (e.g., to warm a cache, or to collect stats).
+        ///
+        /// This function will block the current lunatic process until all of the calls are complete, and
+        /// returns the result of each node next to its id, so that partial failures can be handled.
+        #vis fn #broadcast_fn_ident(#arguments) -> Vec<(lucidity::cluster::NodeId, Result<#return_type, lucidity::JobError>)> {
+            #call_ident::default().broadcast(#closure_arguments)
+        }
+
+        /// The generated [`AbstractProcess`] for the [`lucidity::job`].
+        ///
+        /// This defines the proper methods to achieve synchronous, and asynchronous calls to a process
+        /// that may be local or remote.  All of the generated functions make calls into this processes
+        /// request handlers.
+        #vis struct #service_name_ident {
+            result: #option_return_type,
+            failure: Option<lucidity::JobError>,
+            options: lucidity::CallOptions,
+            args: Option<#arguments_types_list>,
+            worker: Option<(lucidity::lunatic::Tag, u64, u64)>,
+            restarts: lucidity::supervision::Restarts,
+        }
+
+        #[lucidity::lunatic::abstract_process(serializer = lucidity::lunatic::serializer::Bincode)]
+        impl #service_name_ident {
+            #[init]
+            fn init(config: lucidity::lunatic::ap::Config<Self>, (permit, lease, options): (lucidity::dispatcher::Permit, Option<lucidity::limits::Lease>, lucidity::CallOptions)) -> Result<Self, ()> {
+                // Worker deaths are handled in `handle_link_death`, rather than taking the service down with them.
+                config.die_if_link_dies(false);
+
+                // Return the node capacity, and the cluster-wide lease, once this service exits.
+                permit.attach();
+                if let Some(lease) = lease {
+                    lease.attach();
+                }
+
+                let restarts = lucidity::supervision::Restarts::new(&options);
+
+                Ok(Self { result: None, failure: None, options, args: None, worker: None, restarts })
+            }
+
+            #[terminate]
+            fn terminate(self) {
+                // A service that is shut down before its worker delivered a result (e.g., a cancelled duplicate, or a timed out call) cancels the worker.
+                if self.result.is_none() {
+                    if let Some((_, node, process)) = self.worker {
+                        lucidity::supervision::kill(node, process);
+                    }
+                }
+            }
+
+            #[handle_link_death]
+            fn handle_link_death(&mut self, tag: lucidity::lunatic::Tag) {
+                // Only the death of the current worker, before it delivered its result, is a failure.
+                let Some((worker_tag, node, _)) = self.worker else {
+                    return;
+                };
+                if worker_tag != tag || self.result.is_some() {
+                    return;
+                }
+
+                match self.restarts.on_failure(node) {
+                    Some(node) => self.spawn_worker(node),
+                    None => {
+                        self.worker = None;
+                        self.failure = Some(lucidity::JobError::Failed { restarts: self.restarts.total() });
+                    }
+                }
+            }
+
+            #[handle_request]
+            fn #get_ident(&self, #arguments) -> #return_type {
+                #name(#call_arguments)
+            }
+
+            #[handle_request]
+            fn #set_ident(&mut self, value: #return_type) {
+                self.result = Some(value);
+            }
+
+            #[handle_request]
+            fn #try_get_ident(&self) -> Result<#option_return_type, lucidity::JobError> {
+                match &self.failure {
+                    Some(e) => Err(e.clone()),
+                    None => Ok(self.result.clone()),
+                }
+            }
+
+            #[handle_request]
+            fn #async_init_ident(&mut self, #arguments) {
+                // A retried request must not start a second worker.
+                if self.args.is_some() {
+                    return;
+                }
+                self.args = Some(#arguments_tuple);
+
+                self.spawn_worker(lucidity::lunatic::host::node_id());
+            }
+        }
+
+        impl #service_name_ident {
+            /// Spawns a worker for the job on `node`, linked to this service.
+            #[allow(unused_variables)]
+            fn spawn_worker(&mut self, node: u64) {
+                let parent: lucidity::lunatic::ap::ProcessRef<#service_name_ident> = unsafe { lucidity::lunatic::ap::ProcessRef::new(lucidity::lunatic::host::node_id(), lucidity::lunatic::host::process_id()) };
+                let async_set_retry_interval = self.options.async_set_retry_interval;
+                let args = self.args.clone().unwrap();
+
+                let config = self.options.process_config();
+                let tag = lucidity::lunatic::Tag::new();
+
+                let entry: fn((lucidity::lunatic::ap::ProcessRef<#service_name_ident>, std::time::Duration, Option<lucidity::supervision::RemoteLink>, #arguments_types_list), lucidity::lunatic::Mailbox<()>) = |(parent, async_set_retry_interval, link, args), _| {
+                    let result = #name(#arguments_args_tuple_list);
+
+                    loop {
+                        if let Ok(_) = parent.with_timeout(async_set_retry_interval).#set_ident(result.clone()) {
+                            break;
+                        }
+                    }
+
+                    if let Some(link) = link {
+                        link.done();
+                    }
+                };
+
+                let worker = if node == lucidity::lunatic::host::node_id() {
+                    lucidity::lunatic::Process::spawn_link_config_tag(&config, (parent, async_set_retry_interval, None, args), tag, entry)
+                } else {
+                    // Remote processes can not be linked, so a local runner stands in for the worker.
+                    let link = lucidity::supervision::link_remote(node, tag);
+                    let worker = lucidity::lunatic::Process::spawn_node_config(node, &config, (parent, async_set_retry_interval, Some(link.clone()), args), entry);
+                    link.started(worker.id());
+
+                    worker
+                };
+
+                self.worker = Some((tag, node, worker.id()));
+            }
+        }
+
+        /// The [`Job`] type for the generated service.
         ///
         /// This type is usually created with the [`lucidity::job`] macro on the async methods.
         /// The async methods are [`#local_async_fn_ident`] and [`#remote_async_fn_ident`],
         /// and they return this type, so that `try_get` and `await_get` can be called on it.
         ///
         /// When this type is dropped, the underlying process is shutdown.
-        #vis struct #job_name_ident(#vis lucidity::Job<#service_name_ident>);
+        #vis struct #job_name_ident(#vis lucidity::Job<#service_name_ident>, std::time::Duration);
 
         impl #job_name_ident {
+            /// Returns a non-owning handle to the job, which can be copied, and sent to other processes.
+            #vis fn handle(&self) -> #job_ref_ident {
+                #job_ref_ident { process: self.0.process, interval: self.1 }
+            }
+
             /// The `try_get` method on the generated [`Job`] type calls the service process to check if a value is ready.
             ///
             /// This is generally used in some sort of loop, or context where multiple values need to be checked repeatedly.
             /// As the underlying lunatic runtime uses a message-based coroutine paradigm, this method acts as a helper to
             /// synchronize across processes.
+            ///
+            /// Panics if the job failed (see `try_result`).
             #vis fn try_get(&self) -> #option_return_type {
-                loop {
-                    if let Ok(r) = self.0.process.with_timeout(std::time::Duration::from_millis(#async_get_retry_interval_ms)).#try_get_ident() {
-                        return r;
-                    }
-                }
+                self.try_result().unwrap_or_else(|e| panic!("{}", e))
             }
 
             /// The `await_get` method on the generated [`Job`] type calls the service process repeatedly to check if a value is ready.
@@ -591,38 +1074,926 @@ fn job_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
             /// This is achieved by looping over `try_get` with a timeout of `async_get_retry_interval_ms` (default 100ms).
             /// As the underlying lunatic runtime uses a message-based coroutine paradigm, this method acts as a helper to
             /// synchronize across processes.
+            ///
+            /// Panics if the job failed (see `await_result`).
             #vis fn await_get(&self) -> #return_type {
+                self.await_result().unwrap_or_else(|e| panic!("{}", e))
+            }
+
+            /// The `try_result` method is like `try_get`, but returns a [`lucidity::JobError`] if the job failed
+            /// (i.e., its worker died, and was not restarted).
+            #vis fn try_result(&self) -> Result<#option_return_type, lucidity::JobError> {
+                self.handle().try_result()
+            }
+
+            /// The `await_result` method is like `await_get`, but returns a [`lucidity::JobError`] if the job failed
+            /// (i.e., its worker died, and was not restarted).
+            #vis fn await_result(&self) -> Result<#return_type, lucidity::JobError> {
+                self.handle().await_result()
+            }
+        }
+
+        impl lucidity::registry::Registrable for #job_name_ident {
+            type Handle = #job_ref_ident;
+
+            fn process(&self) -> (lucidity::cluster::NodeId, u64) {
+                (self.0.process.node_id(), self.0.process.id())
+            }
+
+            fn from_process(node: lucidity::cluster::NodeId, process: u64) -> #job_ref_ident {
+                <#job_ref_ident as lucidity::registry::Registrable>::from_process(node, process)
+            }
+        }
+
+        /// The generated non-owning handle to a running [`lucidity::job`].
+        ///
+        /// This type is created with `handle` on the [`Job`] type, or looked up in the `lucidity::registry`.  Unlike the [`Job`] type,
+        /// it can be copied, and sent to other processes, and it does not shut the job down when it is dropped.
+        #[derive(Clone, Copy, Debug, lucidity::serde::Serialize, lucidity::serde::Deserialize)]
+        #[serde(crate = "lucidity::serde")]
+        #vis struct #job_ref_ident {
+            process: lucidity::lunatic::ap::ProcessRef<#service_name_ident>,
+            interval: std::time::Duration,
+        }
+
+        impl #job_ref_ident {
+            /// Returns the node that the service process of the job runs on.
+            #vis fn node(&self) -> lucidity::cluster::NodeId {
+                self.process.node_id()
+            }
+
+            /// Calls the service process to check if a value is ready, and returns a [`lucidity::JobError`] if the job failed.
+            #vis fn try_result(&self) -> Result<#option_return_type, lucidity::JobError> {
+                loop {
+                    if let Ok(r) = self.process.with_timeout(self.interval).#try_get_ident() {
+                        return r;
+                    }
+                }
+            }
+
+            /// Calls the service process repeatedly until a value is ready, and returns a [`lucidity::JobError`] if the job failed.
+            #vis fn await_result(&self) -> Result<#return_type, lucidity::JobError> {
                 loop {
-                    if let Ok(r) = self.0.process.with_timeout(std::time::Duration::from_millis(#async_get_retry_interval_ms)).#try_get_ident() {
-                        if let Some(r) = r {
-                            return r;
+                    if let Ok(r) = self.process.with_timeout(self.interval).#try_get_ident() {
+                        if let Some(r) = r? {
+                            return Ok(r);
                         } else {
-                            lucidity::lunatic::sleep(std::time::Duration::from_millis(#async_get_retry_interval_ms));
+                            lucidity::lunatic::sleep(self.interval);
                         }
                     }
                 }
             }
         }
+
+        impl lucidity::registry::Registrable for #job_ref_ident {
+            type Handle = Self;
+
+            fn process(&self) -> (lucidity::cluster::NodeId, u64) {
+                (self.process.node_id(), self.process.id())
+            }
+
+            fn from_process(node: lucidity::cluster::NodeId, process: u64) -> Self {
+                Self {
+                    process: unsafe { lucidity::lunatic::ap::ProcessRef::new(node, process) },
+                    interval: #call_ident::default().options.async_get_retry_interval,
+                }
+            }
+        }
     };
 
     gen
 }
 
-// Tests.
+/// The `actor` macro.
+///
+/// This macro turns an `impl` block into a long-lived, stateful service.  The block must have one `#[init]` method, which builds the state
+/// from the spawn arguments, and any number of `#[request]` (answered) and `#[message]` (fire-and-forget) methods.  In addition to the
+/// block, it creates the underlying [`AbstractProcess`], a spawn builder, and a typed handle to the spawned actor.
+#[proc_macro_attribute]
+pub fn actor(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    actor_inner(TokenStream::from(attr), TokenStream::from(item)).into()
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    //use pretty_assertions::assert_eq;
+/// The names that the handle of an actor, or the [`ProcessRef`] it wraps, already use.
+const RESERVED_ACTOR_METHODS: &[&str] = &[
+    "node",
+    "process",
+    "id",
+    "node_id",
+    "lookup",
+    "register",
+    "is_alive",
+    "link",
+    "link_with",
+    "unlink",
+    "kill",
+    "shutdown",
+    "shutdown_timeout",
+    "send",
+    "request",
+    "deferred_request",
+    "with_timeout",
+    "with_delay",
+];
+
+/// The kind of a method in the `impl` block of an actor.
+enum ActorMethod {
+    Init,
+    Request,
+    Message,
+}
 
-    #[test]
-    fn test_job() {
-        let input = quote! {
-            fn pythagorean(num1: u32, num2: u32) -> f32 {
-                ((num1 * num1 + num2 * num2) as f32).sqrt()
+fn actor_inner(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut input = syn::parse2::<ItemImpl>(item).unwrap();
+
+    // Parse code, and get identifiers.
+
+    let self_ty = &input.self_ty;
+    let name = match &**self_ty {
+        syn::Type::Path(path) if input.generics.params.is_empty() => path.path.segments.last().expect("Invalid actor type.").ident.clone(),
+        _ => panic!("The `actor` attribute only supports `impl` blocks of non-generic types."),
+    };
+
+    // Sort the methods, and strip the actor attributes from them.
+
+    let mut init = None;
+    let mut handlers = Vec::new();
+    for item in input.items.iter_mut() {
+        let syn::ImplItem::Fn(method) = item else {
+            continue;
+        };
+
+        let mut kind = None;
+        method.attrs.retain(|a| {
+            let found = if a.path().is_ident("init") {
+                Some(ActorMethod::Init)
+            } else if a.path().is_ident("request") {
+                Some(ActorMethod::Request)
+            } else if a.path().is_ident("message") {
+                Some(ActorMethod::Message)
+            } else {
+                None
+            };
+
+            match found {
+                Some(found) => {
+                    kind = Some(found);
+                    false
+                }
+                None => true,
+            }
+        });
+
+        match kind {
+            Some(ActorMethod::Init) => {
+                if init.is_some() {
+                    panic!("The `actor` attribute requires exactly one `#[init]` method.");
+                }
+
+                init = Some(method.sig.clone());
+            }
+            Some(kind) => {
+                let method_name = method.sig.ident.to_string();
+                if RESERVED_ACTOR_METHODS.contains(&method_name.as_str()) {
+                    panic!("The actor method name `{}` is reserved.", method_name);
+                }
+
+                match method.sig.inputs.first() {
+                    Some(syn::FnArg::Receiver(receiver)) if receiver.reference.is_some() => {}
+                    _ => panic!("The actor method `{}` must take `&self`, or `&mut self`.", method_name),
+                }
+
+                let docs = method.attrs.iter().filter(|a| a.path().is_ident("doc")).cloned().collect::<Vec<_>>();
+                handlers.push((kind, method.sig.clone(), docs));
+            }
+            None => {}
+        }
+    }
+    let Some(init) = init else {
+        panic!("The `actor` attribute requires exactly one `#[init]` method.");
+    };
+
+    // Get argument information.
+
+    let typed_arguments = |sig: &syn::Signature| {
+        sig.inputs
+            .iter()
+            .filter_map(|arg| match arg {
+                syn::FnArg::Typed(pat_type) => match &*pat_type.pat {
+                    syn::Pat::Ident(ident) => Some((ident.ident.clone(), (*pat_type.ty).clone())),
+                    _ => panic!("Invalid argument pattern."),
+                },
+                syn::FnArg::Receiver(_) => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let init_ident = &init.ident;
+    let init_arguments = typed_arguments(&init);
+    let init_names = init_arguments.iter().map(|(n, _)| n).collect::<Vec<_>>();
+    let init_types = init_arguments.iter().map(|(_, t)| t).collect::<Vec<_>>();
+    let arguments = quote! { #(#init_names: #init_types),* };
+    let arguments_types_list = if init_arguments.len() == 1 {
+        quote! { #(#init_types)* }
+    } else {
+        quote! { (#(#init_types),*) }
+    };
+    let arguments_tuple = if init_arguments.len() == 1 {
+        quote! { #(#init_names)* }
+    } else {
+        quote! { (#(#init_names),*) }
+    };
+
+    // Names of generated identifiers.
+
+    let actor_ident = Ident::new(&format!("{}Actor", name), name.span());
+    let spawn_ident = Ident::new(&format!("{}Spawn", name), name.span());
+    let ref_ident = Ident::new(&format!("{}Ref", name), name.span());
+
+    // Generate the handlers.
+
+    let mut process_handlers = Vec::new();
+    let mut ref_methods = Vec::new();
+    for (kind, sig, docs) in &handlers {
+        let method = &sig.ident;
+        let method_arguments = typed_arguments(sig);
+        let names = method_arguments.iter().map(|(n, _)| n).collect::<Vec<_>>();
+        let types = method_arguments.iter().map(|(_, t)| t).collect::<Vec<_>>();
+        let return_type = match &sig.output {
+            syn::ReturnType::Default => quote! { () },
+            syn::ReturnType::Type(_, ty) => quote! { #ty },
+        };
+
+        match kind {
+            ActorMethod::Request => {
+                process_handlers.push(quote! {
+                    #[handle_request]
+                    fn #method(&mut self #(, #names: #types)*) -> #return_type {
+                        self.state.#method(#(#names),*)
+                    }
+                });
+
+                ref_methods.push(quote! {
+                    #(#docs)*
+                    pub fn #method(&self #(, #names: #types)*) -> Result<#return_type, lucidity::JobError> {
+                        match self.timeout {
+                            Some(timeout) => self.process.with_timeout(timeout).#method(#(#names),*).map_err(|_| self.timed_out()),
+                            None => Ok(self.process.#method(#(#names),*)),
+                        }
+                    }
+                });
+            }
+            _ => {
+                process_handlers.push(quote! {
+                    #[handle_message]
+                    fn #method(&mut self #(, #names: #types)*) {
+                        self.state.#method(#(#names),*);
+                    }
+                });
+
+                ref_methods.push(quote! {
+                    #(#docs)*
+                    pub fn #method(&self #(, #names: #types)*) {
+                        self.process.#method(#(#names),*);
+                    }
+                });
+            }
+        }
+    }
+
+    // Parse the attributes.
+
+    let attr = parse_attributes(attr);
+
+    // Set the attributes.
+
+    let mut init_retry_interval_ms = 100;
+    let mut shutdown_retry_interval_ms = 100;
+    let mut memory = 100u64 * 1024 * 1024;
+    // Fuel is counted over the whole life of a process, so actors are not limited by default.
+    let mut fuel = 0u64;
+    let mut priority = 0u32;
+    let mut rate_limit = None;
+    let mut max_concurrency = None;
+    let mut requires = Vec::new();
+    for (key, value) in &attr {
+        let key = key.to_string();
+        let key = key.as_str();
+
+        match key {
+            "init_retry_interval_ms" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u64>() {
+                    Ok(v) => {
+                        init_retry_interval_ms = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "shutdown_retry_interval_ms" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u64>() {
+                    Ok(v) => {
+                        shutdown_retry_interval_ms = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "memory" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u64>() {
+                    Ok(v) => {
+                        memory = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "fuel" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u64>() {
+                    Ok(v) => {
+                        fuel = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "priority" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<u32>() {
+                    Ok(v) => {
+                        priority = v;
+                    }
+                    Err(_) => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "rate_limit" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<f64>() {
+                    Ok(v) if v > 0.0 => {
+                        rate_limit = Some(v);
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "max_concurrency" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                match value.parse::<usize>() {
+                    Ok(v) if v > 0 => {
+                        max_concurrency = Some(v);
+                    }
+                    _ => panic!("Invalid attribute argument value `{}`.", value),
+                }
+            }
+            "requires" => {
+                let value = value.to_string();
+                let value = value.as_str();
+
+                let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+                    panic!("Invalid attribute argument value `{}`.", value);
+                };
+
+                for requirement in inner.split(',') {
+                    match requirement.split_once('=') {
+                        Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                            requires.push((key.trim().to_owned(), value.trim().to_owned()));
+                        }
+                        _ => panic!("Invalid attribute argument value `{}`.", value),
+                    }
+                }
+            }
+            _ => panic!("Invalid attribute argument name `{}`.", key),
+        }
+    }
+
+    // Get some special quotes.
+
+    let requires = requires.iter().map(|(key, value)| quote! { (String::from(#key), String::from(#value)) });
+
+    let default_options = quote! {
+        lucidity::CallOptions {
+            memory: #memory,
+            fuel: #fuel,
+            priority: lucidity::dispatcher::priority_or(#priority),
+            requires: vec![#(#requires),*],
+            init_retry_interval: std::time::Duration::from_millis(#init_retry_interval_ms),
+            shutdown_retry_interval: std::time::Duration::from_millis(#shutdown_retry_interval_ms),
+            ..Default::default()
+        }
+    };
+
+    let limits = if rate_limit.is_some() || max_concurrency.is_some() {
+        let rate_limit = match rate_limit {
+            Some(r) => quote! { Some(#r) },
+            None => quote! { None },
+        };
+        let max_concurrency = match max_concurrency {
+            Some(m) => quote! { Some(#m) },
+            None => quote! { None },
+        };
+
+        quote! { Some(lucidity::limits::Limits { rate_limit: #rate_limit, max_concurrency: #max_concurrency }) }
+    } else {
+        quote! { None }
+    };
+
+    let actor_name = quote! { concat!(module_path!(), "::", stringify!(#name)) };
+
+    // Generate the code.
+
+    let gen = quote! {
+        #input
+
+        impl #name {
+            /// Returns a spawn builder for the [`lucidity::actor`], with the values of its attribute as defaults.
+            pub fn spawn() -> #spawn_ident {
+                #spawn_ident::default()
+            }
+
+            /// Spawns the [`lucidity::actor`] on the local node, returning a handle to it.
+            pub fn spawn_local(#arguments) -> Result<#ref_ident, lucidity::JobError> {
+                #spawn_ident::default().local(#(#init_names),*)
+            }
+
+            /// Spawns the [`lucidity::actor`] on a remote node, returning a handle to it.
+            pub fn spawn_remote(#arguments) -> Result<#ref_ident, lucidity::JobError> {
+                #spawn_ident::default().remote(#(#init_names),*)
+            }
+
+            /// Spawns the [`lucidity::actor`] on a specific node (which may be the local node), returning a handle to it.
+            ///
+            /// Fails with [`lucidity::JobError::UnknownNode`] if the node is not connected.
+            pub fn spawn_on(node: lucidity::cluster::NodeId, #arguments) -> Result<#ref_ident, lucidity::JobError> {
+                #spawn_ident::default().node(node).remote(#(#init_names),*)
+            }
+        }
+
+        impl lucidity::actor::Actor for #self_ty {
+            type Args = #arguments_types_list;
+            type Process = #actor_ident;
+            type Ref = #ref_ident;
+
+            fn name() -> &'static str {
+                #actor_name
+            }
+
+            fn options() -> lucidity::CallOptions {
+                #default_options
+            }
+
+            fn limits() -> Option<lucidity::limits::Limits> {
+                #limits
+            }
+
+            fn handle(process: lucidity::lunatic::ap::ProcessRef<#actor_ident>, options: &lucidity::CallOptions) -> #ref_ident {
+                #ref_ident {
+                    process,
+                    timeout: options.timeout,
+                    shutdown_retry_interval: options.shutdown_retry_interval,
+                }
+            }
+        }
+
+        /// The generated spawn builder for the [`lucidity::actor`].
+        ///
+        /// This is a helper for cases where you want to override the options of the `lucidity::actor` attribute
+        /// (e.g., `memory`, or `fuel`) for a single actor.  It is created with `spawn()`, and the values of the
+        /// attribute are the defaults.
+        #[derive(Clone, Debug)]
+        pub struct #spawn_ident {
+            options: lucidity::CallOptions,
+        }
+
+        impl Default for #spawn_ident {
+            fn default() -> Self {
+                Self { options: <#self_ty as lucidity::actor::Actor>::options() }
+            }
+        }
+
+        impl #spawn_ident {
+            /// Sets the maximum memory of the actor's process.
+            pub fn memory(mut self, memory: u64) -> Self {
+                self.options.memory = memory;
+                self
+            }
+
+            /// Sets the maximum fuel of the actor's process.
+            pub fn fuel(mut self, fuel: u64) -> Self {
+                self.options.fuel = fuel;
+                self
+            }
+
+            /// Sets the priority of the spawn on a saturated node.
+            pub fn priority(mut self, priority: u32) -> Self {
+                self.options.priority = priority;
+                self
+            }
+
+            /// Sets the node that remote spawns run on, rather than a node picked by the placement scheme.
+            pub fn node(mut self, node: u64) -> Self {
+                self.options.node = Some(node);
+                self
+            }
+
+            /// Sets the amount of time after which the spawn, and each request on the returned handle, fails with [`lucidity::JobError::Timeout`].
+            pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+                self.options.timeout = Some(timeout);
+                self
+            }
+
+            /// Sets the labels that a node must have to run the actor, in the form `key=value,key=value` (e.g., `region=ord,size=large`).
+            pub fn requires(mut self, requirements: &str) -> Self {
+                self.options.requires = lucidity::call::parse_requirements(requirements);
+                self
+            }
+
+            /// Sets the retry interval when starting the actor's process.
+            pub fn init_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.init_retry_interval = interval;
+                self
+            }
+
+            /// Sets the retry interval when shutting the actor's process down.
+            pub fn shutdown_retry_interval(mut self, interval: std::time::Duration) -> Self {
+                self.options.shutdown_retry_interval = interval;
+                self
+            }
+
+            /// Spawns the [`lucidity::actor`] on the local node, returning a handle to it.
+            pub fn local(&self, #arguments) -> Result<#ref_ident, lucidity::JobError> {
+                self.start(self.options.local_node()?, #arguments_tuple)
+            }
+
+            /// Spawns the [`lucidity::actor`] on a remote node, returning a handle to it.
+            pub fn remote(&self, #arguments) -> Result<#ref_ident, lucidity::JobError> {
+                self.start(self.options.remote_node()?, #arguments_tuple)
+            }
+
+            fn start(&self, node: u64, args: #arguments_types_list) -> Result<#ref_ident, lucidity::JobError> {
+                let deadline = self.options.deadline();
+
+                // Unless the spawn is pinned to the node, place it elsewhere when the node is unavailable.
+                let process = lucidity::call::place(node, &self.options, false, deadline, |node| {
+                    lucidity::call::start_with::<#actor_ident>(node, #actor_name, <#self_ty as lucidity::actor::Actor>::limits(), &self.options, deadline, |permit, lease| (permit, lease, args.clone()))
+                })?;
+
+                Ok(<#self_ty as lucidity::actor::Actor>::handle(process, &self.options))
+            }
+        }
+
+        /// The generated handle to a spawned [`lucidity::actor`].
+        ///
+        /// The handle is cheap to copy, and can be sent to other processes (on any node).  Requests return a
+        /// [`lucidity::JobError`] when they time out; they are never retried, since handlers may not be idempotent.
+        /// Without a timeout, a request to an actor that exited blocks forever.
+        ///
+        /// Unlike a [`Job`], the actor keeps running when its handles are dropped, until `shutdown` is called.
+        #[derive(Clone, Copy, Debug, lucidity::serde::Serialize, lucidity::serde::Deserialize)]
+        #[serde(crate = "lucidity::serde")]
+        pub struct #ref_ident {
+            process: lucidity::lunatic::ap::ProcessRef<#actor_ident>,
+            timeout: Option<std::time::Duration>,
+            shutdown_retry_interval: std::time::Duration,
+        }
+
+        impl #ref_ident {
+            /// Returns the node that the actor runs on.
+            pub fn node(&self) -> lucidity::cluster::NodeId {
+                self.process.node_id()
+            }
+
+            /// Returns the underlying process of the actor.
+            pub fn process(&self) -> lucidity::lunatic::ap::ProcessRef<#actor_ident> {
+                self.process
+            }
+
+            /// Returns a copy of the handle whose requests fail with [`lucidity::JobError::Timeout`] after `timeout`.
+            pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+                self.timeout = Some(timeout);
+                self
+            }
+
+            /// Shuts the actor down, retrying every `shutdown_retry_interval_ms` (default 100ms).
+            ///
+            /// Fails with [`lucidity::JobError::UnknownNode`] if the node of the actor left the cluster, or with
+            /// [`lucidity::JobError::Timeout`] if the handle has a timeout, and it passes first.
+            pub fn shutdown(self) -> Result<(), lucidity::JobError> {
+                let deadline = self.timeout.map(|t| std::time::Instant::now() + t);
+
+                loop {
+                    if self.process.with_timeout(self.shutdown_retry_interval).shutdown().is_ok() {
+                        return Ok(());
+                    }
+
+                    if !lucidity::cluster::is_connected(self.node()) {
+                        return Err(lucidity::JobError::UnknownNode(self.node()));
+                    }
+
+                    if lucidity::call::expired(deadline) {
+                        return Err(lucidity::JobError::Timeout);
+                    }
+                }
+            }
+
+            #(#ref_methods)*
+
+            fn timed_out(&self) -> lucidity::JobError {
+                if lucidity::cluster::is_connected(self.node()) {
+                    lucidity::JobError::Timeout
+                } else {
+                    lucidity::JobError::UnknownNode(self.node())
+                }
+            }
+        }
+
+        impl lucidity::registry::Registrable for #ref_ident {
+            type Handle = Self;
+
+            fn process(&self) -> (lucidity::cluster::NodeId, u64) {
+                (self.process.node_id(), self.process.id())
+            }
+
+            fn from_process(node: lucidity::cluster::NodeId, process: u64) -> Self {
+                let process = unsafe { lucidity::lunatic::ap::ProcessRef::new(node, process) };
+
+                <#self_ty as lucidity::actor::Actor>::handle(process, &<#self_ty as lucidity::actor::Actor>::options())
+            }
+        }
+
+        /// The generated [`AbstractProcess`] for the [`lucidity::actor`].
+        ///
+        /// This owns the state of the actor, and forwards the requests, and messages, of the handle to it.
+        pub struct #actor_ident {
+            state: #self_ty,
+        }
+
+        #[lucidity::lunatic::abstract_process(serializer = lucidity::lunatic::serializer::Bincode, visibility = pub)]
+        impl #actor_ident {
+            #[init]
+            fn init(_: lucidity::lunatic::ap::Config<Self>, (permit, lease, #arguments_tuple): (lucidity::dispatcher::Permit, Option<lucidity::limits::Lease>, #arguments_types_list)) -> Result<Self, ()> {
+                // Hold the node capacity, and the cluster-wide lease, for as long as the actor runs.
+                permit.attach();
+                if let Some(lease) = lease {
+                    lease.attach();
+                }
+
+                Ok(Self { state: <#self_ty>::#init_ident(#(#init_names),*) })
+            }
+
+            #(#process_handlers)*
+        }
+    };
+
+    gen
+}
+
+/// Splits the arguments of an attribute into `key = value` pairs.
+fn parse_attributes(attr: TokenStream) -> Vec<(TokenTree, TokenTree)> {
+    let attr = attr.into_iter().collect::<Vec<_>>();
+
+    attr.split(|t| match t {
+        TokenTree::Punct(punct) => punct.as_char() == ',',
+        _ => false,
+    })
+    .map(|tt| {
+        tt.split(|t| match t {
+            TokenTree::Punct(punct) => punct.as_char() == '=',
+            _ => false,
+        })
+        .flat_map(ToOwned::to_owned)
+        .collect::<Vec<_>>()
+    })
+    .filter_map(|v| {
+        if v.len() != 2 {
+            return None;
+        }
+
+        Some((v[0].clone(), v[1].clone()))
+    })
+    .collect()
+}
+
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_job() {
+        let input = quote! {
+            fn pythagorean(num1: u32, num2: u32) -> f32 {
+                ((num1 * num1 + num2 * num2) as f32).sqrt()
+            }
+        };
+
+        let output = job_inner(TokenStream::new(), input).to_string();
+
+        assert!(output.contains("fn pythagorean_broadcast"));
+        assert!(output.contains("fn pythagorean_on"));
+        assert!(output.contains("fn pythagorean_on_async"));
+        assert!(output.contains("struct PythagoreanJobRef"));
+    }
+
+    #[test]
+    fn test_job_with_attributes() {
+        let attr = quote! {
+            memory = 1024, fuel = 100, fanout = "random", priority = 10, rate_limit = 50, max_concurrency = 10
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let output = job_inner(attr, input).to_string();
+
+        assert!(output.contains("priority_or (10u32)"));
+        assert!(output.contains("fanout : lucidity :: Fanout :: Random"));
+        assert!(output.contains("struct SquareCall"));
+        assert!(output.contains("rate_limit : Some (50f64)"));
+        assert!(output.contains("max_concurrency : Some (10usize)"));
+    }
+
+    #[test]
+    fn test_job_with_restart() {
+        let attr = quote! {
+            restart = "permanent", restart_node = "different", max_restarts = 5, restart_period_ms = 1000
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let output = job_inner(attr, input).to_string();
+
+        assert!(output.contains("restart : lucidity :: Restart :: Permanent"));
+        assert!(output.contains("restart_node : lucidity :: RestartNode :: Different"));
+        assert!(output.contains("max_restarts : 5u32"));
+        assert!(output.contains("from_millis (1000u64)"));
+    }
+
+    #[test]
+    fn test_job_with_speculation() {
+        let attr = quote! {
+            idempotent = true, speculative = 80, speculative_percentile = 95
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let output = job_inner(attr, input).to_string();
+
+        assert!(output.contains("speculation : Some (lucidity :: Speculation { quorum : 80f64 , percentile : 95f64 })"));
+        assert!(output.contains("fn speculative"));
+    }
+
+    #[test]
+    #[should_panic(expected = "requires `idempotent = true`")]
+    fn test_job_with_speculation_requires_idempotent() {
+        let attr = quote! {
+            speculative = 80
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let _ = job_inner(attr, input);
+    }
+
+    #[test]
+    fn test_job_with_requirements() {
+        let attr = quote! {
+            requires = "region=ord, size=large"
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let output = job_inner(attr, input).to_string();
+
+        assert!(output.contains("requires : vec ! [(String :: from (\"region\") , String :: from (\"ord\")) , (String :: from (\"size\") , String :: from (\"large\"))]"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid attribute argument value")]
+    fn test_job_with_invalid_requirements() {
+        let attr = quote! {
+            requires = "region"
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let _ = job_inner(attr, input);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid attribute argument value")]
+    fn test_job_with_invalid_restart() {
+        let attr = quote! {
+            restart = "sometimes"
+        };
+        let input = quote! {
+            fn square(a: u32) -> u32 {
+                a * a
+            }
+        };
+
+        let _ = job_inner(attr, input);
+    }
+
+    #[test]
+    fn test_actor() {
+        let attr = quote! {
+            memory = 1024, max_concurrency = 2
+        };
+        let input = quote! {
+            impl Counter {
+                #[init]
+                fn new(start: u64) -> Self {
+                    Self { count: start }
+                }
+
+                #[request]
+                fn increment(&mut self, by: u64) -> u64 {
+                    self.count += by;
+                    self.count
+                }
+
+                #[message]
+                fn reset(&mut self) {
+                    self.count = 0;
+                }
+            }
+        };
+
+        let output = actor_inner(attr, input).to_string();
+
+        assert!(output.contains("struct CounterActor"));
+        assert!(output.contains("struct CounterRef"));
+        assert!(output.contains("impl lucidity :: actor :: Actor for Counter"));
+        assert!(output.contains("impl lucidity :: registry :: Registrable for CounterRef"));
+        assert!(output.contains("fn spawn_remote (start : u64)"));
+        assert!(output.contains("fn increment (& self , by : u64) -> Result < u64 , lucidity :: JobError >"));
+        assert!(output.contains("fn reset (& self)"));
+        assert!(output.contains("max_concurrency : Some (2usize)"));
+        assert!(!output.contains("# [request]"));
+    }
+
+    #[test]
+    #[should_panic(expected = "exactly one `#[init]` method")]
+    fn test_actor_without_init() {
+        let input = quote! {
+            impl Counter {
+                #[request]
+                fn count(&self) -> u64 {
+                    self.count
+                }
+            }
+        };
+
+        let _ = actor_inner(TokenStream::new(), input);
+    }
+
+    #[test]
+    #[should_panic(expected = "is reserved")]
+    fn test_actor_with_reserved_method() {
+        let input = quote! {
+            impl Counter {
+                #[init]
+                fn new() -> Self {
+                    Self { count: 0 }
+                }
+
+                #[message]
+                fn shutdown(&mut self) {}
             }
         };
 
-        let _ = job_inner(TokenStream::new(), input).to_string();
+        let _ = actor_inner(TokenStream::new(), input);
     }
 }
@@ -0,0 +1,156 @@
+//! Scaling worker pools with the load of their nodes.
+
+use std::time::{Duration, Instant};
+
+use lucidity_core::{
+    cluster::Cluster,
+    dispatcher,
+    lunatic::{serializer::Bincode, Mailbox, Process},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{ensure_machines, list_machines, reconcile, FlyClusterSpec, FlyConfig, MachineState, MACHINE_LABEL};
+
+/// The amount of time after which a node whose dispatcher does not answer is left out of the load of its pool.
+pub(crate) const STATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How an [`Autoscaler`] sizes its pool.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutoscalePolicy {
+    /// The minimum number of machines.
+    pub min: usize,
+    /// The maximum number of machines.
+    pub max: usize,
+    /// The share of the capacity of each node that the pool aims to use, between 0 and 1.
+    pub target_utilization: f64,
+    /// The minimum amount of time between a change of the pool, and the next growth.
+    pub scale_up_cooldown: Duration,
+    /// The minimum amount of time between a change of the pool, and the next shrink.
+    pub scale_down_cooldown: Duration,
+    /// The interval between checks of the pool.
+    pub interval: Duration,
+}
+
+impl Default for AutoscalePolicy {
+    fn default() -> Self {
+        Self {
+            min: 1,
+            max: 10,
+            target_utilization: 0.75,
+            scale_up_cooldown: Duration::from_secs(60),
+            scale_down_cooldown: Duration::from_secs(5 * 60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The load of a pool, as the dispatchers of its nodes report it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PoolLoad {
+    /// The jobs that run, or wait to run, on the nodes of the pool.
+    pub jobs: usize,
+    /// The number of jobs that the nodes of the pool may run concurrently.
+    pub capacity: usize,
+    /// The number of nodes of the pool.
+    pub nodes: usize,
+    /// The highest index of a node of the pool that runs, or queues, a job.
+    pub busiest: usize,
+}
+
+/// Returns the number of machines that `load` calls for, between the bounds of `policy`.
+///
+/// The pool never shrinks below its busiest node, so that shrinking does not kill running jobs, and an empty pool with `demand` (i.e., placements
+/// that found no node) grows to at least one machine.
+pub(crate) fn desired_count(load: &PoolLoad, demand: bool, policy: &AutoscalePolicy) -> usize {
+    let per_node = if load.nodes == 0 { dispatcher::DEFAULT_CAPACITY } else { load.capacity / load.nodes }.max(1);
+    let target = (per_node as f64 * policy.target_utilization.clamp(0.01, 1.0)).max(1.0);
+
+    let mut desired = (load.jobs as f64 / target).ceil() as usize;
+    desired = desired.max(load.busiest);
+    if demand {
+        desired = desired.max(1);
+    }
+
+    desired.clamp(policy.min, policy.max.max(policy.min))
+}
+
+/// A process, on the local node, that grows and shrinks a pool with the load of its nodes.
+///
+/// Every `interval`, the autoscaler sums the running, and pending, jobs of the nodes of the pool, and sizes the pool so that each node runs
+/// `target_utilization` of its capacity.  Large fanouts queue on the nodes of the pool, so they grow it, and it shrinks again once they finish.
+#[derive(Debug)]
+pub struct Autoscaler {
+    process: Process<(), Bincode>,
+}
+
+impl Autoscaler {
+    /// Starts scaling the pool of `spec`, starting from its count (within the bounds of `policy`).
+    pub fn start(config: &FlyConfig, spec: &FlyClusterSpec, policy: AutoscalePolicy) -> Self {
+        let process = Process::spawn((config.clone(), spec.clone(), policy), autoscale);
+
+        Self { process }
+    }
+
+    /// Stops scaling the pool, and leaves its machines as they are.
+    pub fn stop(self) {
+        self.process.kill();
+    }
+}
+
+fn autoscale((config, spec, policy): (FlyConfig, FlyClusterSpec, AutoscalePolicy), _: Mailbox<(), Bincode>) {
+    let cluster = Cluster::get();
+    let mut count = spec.machine_count().clamp(policy.min, policy.max.max(policy.min));
+    let mut changed: Option<Instant> = None;
+    let mut unplaced = cluster.unplaced();
+
+    loop {
+        crate::lunatic::sleep(policy.interval);
+
+        // Machines may have been removed (or added) outside of the autoscaler, so the count is read back from the API.
+        if let Ok(machines) = list_machines(&config) {
+            count = machines
+                .iter()
+                .filter(|m| reconcile::index(&spec, &m.name).is_some() && !matches!(m.state, MachineState::Destroying | MachineState::Destroyed))
+                .count();
+        }
+
+        let demand = cluster.unplaced();
+        let desired = desired_count(&load(&cluster, &spec), demand != unplaced, &policy);
+        unplaced = demand;
+
+        let cooldown = if desired > count { policy.scale_up_cooldown } else { policy.scale_down_cooldown };
+        if desired == count || changed.map_or(false, |c| c.elapsed() < cooldown) {
+            continue;
+        }
+
+        if ensure_machines(&config, &spec.clone().count(desired)).is_ok() {
+            count = desired;
+            changed = Some(Instant::now());
+        }
+    }
+}
+
+/// Returns the load of the nodes of the pool of `spec`.
+///
+/// Nodes that do not answer (e.g., while they stop) are left out.
+fn load(cluster: &Cluster, spec: &FlyClusterSpec) -> PoolLoad {
+    let mut load = PoolLoad::default();
+
+    for node in reconcile::pool_nodes(cluster, spec) {
+        let Some(stats) = dispatcher::stats_timeout(node.id, STATS_TIMEOUT) else {
+            continue;
+        };
+        let jobs = stats.running + stats.pending;
+
+        load.jobs += jobs;
+        load.capacity += stats.capacity;
+        load.nodes += 1;
+
+        if jobs > 0 {
+            let k = node.label(MACHINE_LABEL).and_then(|m| reconcile::index(spec, m)).unwrap_or_default();
+            load.busiest = load.busiest.max(k);
+        }
+    }
+
+    load
+}
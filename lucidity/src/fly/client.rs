@@ -0,0 +1,354 @@
+//! A typed client for the fly.io Machines API.
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use nightfly::{HttpResponse, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::FlyError;
+
+/// The public endpoint of the Machines API.
+pub const ENDPOINT: &str = "https://api.machines.dev/v1";
+
+/// The longest wait that the `wait` endpoint accepts.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// The number of times that a request is retried after a `429`, or a `5xx`, by default.
+const DEFAULT_RETRIES: u32 = 5;
+
+/// The wait before the first retry of a request whose response has no `Retry-After`, which doubles with every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest backoff between two tries of a request.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The state of a machine.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MachineState {
+    /// The machine was created, but has not started yet.
+    Created,
+    /// The machine is starting.
+    Starting,
+    /// The machine is running.
+    Started,
+    /// The machine is stopping.
+    Stopping,
+    /// The machine is stopped.
+    Stopped,
+    /// The machine is being replaced by an update.
+    Replacing,
+    /// The machine is being destroyed.
+    Destroying,
+    /// The machine is destroyed.
+    Destroyed,
+    /// A state that this client does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl MachineState {
+    /// Returns the name of the state in the Machines API.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineState::Created => "created",
+            MachineState::Starting => "starting",
+            MachineState::Started => "started",
+            MachineState::Stopping => "stopping",
+            MachineState::Stopped => "stopped",
+            MachineState::Replacing => "replacing",
+            MachineState::Destroying => "destroying",
+            MachineState::Destroyed => "destroyed",
+            MachineState::Unknown => "unknown",
+        }
+    }
+}
+
+/// The size of a machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestConfig {
+    /// The kind of CPU (i.e., `shared`, or `performance`).
+    #[serde(default)]
+    pub cpu_kind: String,
+    /// The number of CPUs.
+    #[serde(default)]
+    pub cpus: u32,
+    /// The amount of memory, in MB.
+    #[serde(default)]
+    pub memory_mb: u32,
+}
+
+/// The command that a machine runs when it starts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitConfig {
+    /// The command (and arguments) to run instead of the image's entrypoint, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec: Option<Vec<String>>,
+}
+
+/// What the platform does when the main process of a machine exits.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartConfig {
+    /// The restart policy (i.e., `no`, `always`, or `on-failure`).
+    #[serde(default)]
+    pub policy: String,
+}
+
+/// A port that a machine exposes on its private network address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// The protocol of the port (i.e., `tcp`, or `udp`).
+    pub protocol: String,
+    /// The port inside the machine.
+    pub internal_port: u16,
+}
+
+/// The configuration of a machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineConfig {
+    /// The image that the machine runs.
+    #[serde(default)]
+    pub image: String,
+    /// The environment variables of the machine.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// The command that the machine runs when it starts.
+    #[serde(default)]
+    pub init: InitConfig,
+    /// The size of the machine.
+    #[serde(default)]
+    pub guest: GuestConfig,
+    /// What the platform does when the main process of the machine exits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart: Option<RestartConfig>,
+    /// The ports that the machine exposes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceConfig>,
+    /// Whether the machine is destroyed once its main process exits.
+    #[serde(default)]
+    pub auto_destroy: bool,
+    /// The metadata of the machine.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// A machine, as the Machines API reports it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Machine {
+    /// The id of the machine.
+    pub id: String,
+    /// The name of the machine.
+    #[serde(default)]
+    pub name: String,
+    /// The state of the machine.
+    pub state: MachineState,
+    /// The region of the machine.
+    #[serde(default)]
+    pub region: String,
+    /// The id of the current version of the machine, which changes with every update.
+    #[serde(default)]
+    pub instance_id: String,
+    /// The private network address of the machine.
+    #[serde(default)]
+    pub private_ip: String,
+    /// The configuration of the machine.
+    #[serde(default)]
+    pub config: MachineConfig,
+}
+
+#[derive(Serialize)]
+struct CreateMachine<'a> {
+    name: &'a str,
+    region: &'a str,
+    config: &'a MachineConfig,
+}
+
+#[derive(Serialize)]
+struct UpdateMachine<'a> {
+    config: &'a MachineConfig,
+}
+
+/// A client for the machines of one fly.io app.
+#[derive(Clone)]
+pub struct FlyClient {
+    key: String,
+    app_name: String,
+    endpoint: String,
+    retries: u32,
+}
+
+impl FlyClient {
+    /// Creates a client for the machines of `app_name`, which authenticates with the API token `key`.
+    pub fn new(key: &str, app_name: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            app_name: app_name.to_owned(),
+            endpoint: ENDPOINT.to_owned(),
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Sets the endpoint of the Machines API (defaults to [`ENDPOINT`]).
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the number of times that a request is retried after a `429`, or a `5xx` (defaults to 5).
+    ///
+    /// Each retry waits for the `Retry-After` of the response (up to 30 seconds), or else backs off exponentially.  Creating a machine is only
+    /// retried after a `429` (see [`FlyClient::create`]).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Returns the name of the app.
+    pub fn app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    /// Creates (and starts) the machine `name` in `region`.
+    ///
+    /// Creating a machine is not idempotent, so only a `429` is retried: a `5xx` may come after the machine was created, and a retry would
+    /// create a duplicate.
+    pub fn create(&self, name: &str, region: &str, config: &MachineConfig) -> Result<Machine, FlyError> {
+        let body = CreateMachine { name, region, config };
+
+        self.send_with(|| nightfly::Client::new().post(self.url("machines")).json(&body), false)?.json()
+    }
+
+    /// Returns the machine `id`.
+    pub fn get(&self, id: &str) -> Result<Machine, FlyError> {
+        self.send(|| nightfly::Client::new().get(self.url(&format!("machines/{}", id))))?.json()
+    }
+
+    /// Returns the machines of the app.
+    pub fn list(&self) -> Result<Vec<Machine>, FlyError> {
+        self.send(|| nightfly::Client::new().get(self.url("machines")))?.json()
+    }
+
+    /// Replaces the configuration of the machine `id`, which restarts it.
+    pub fn update(&self, id: &str, config: &MachineConfig) -> Result<Machine, FlyError> {
+        let body = UpdateMachine { config };
+
+        self.send(|| nightfly::Client::new().post(self.url(&format!("machines/{}", id))).json(&body))?.json()
+    }
+
+    /// Starts the machine `id`.
+    pub fn start(&self, id: &str) -> Result<(), FlyError> {
+        self.send(|| nightfly::Client::new().post(self.url(&format!("machines/{}/start", id))))?;
+
+        Ok(())
+    }
+
+    /// Stops the machine `id`.
+    pub fn stop(&self, id: &str) -> Result<(), FlyError> {
+        self.send(|| nightfly::Client::new().post(self.url(&format!("machines/{}/stop", id))))?;
+
+        Ok(())
+    }
+
+    /// Waits until the machine `id` reaches `state`, for at most `timeout` (capped at one minute by the API).
+    ///
+    /// It fails with [`FlyError::Timeout`] if the machine does not reach `state` in time.
+    pub fn wait(&self, id: &str, state: MachineState, timeout: Duration) -> Result<(), FlyError> {
+        let timeout = timeout.min(MAX_WAIT).as_secs().max(1).to_string();
+        let request = || {
+            nightfly::Client::new()
+                .get(self.url(&format!("machines/{}/wait", id)))
+                .query(&[("state", state.as_str()), ("timeout", timeout.as_str())])
+        };
+
+        match self.send(request) {
+            Err(FlyError::Api { status: 408, .. }) => Err(FlyError::Timeout(format!("The machine {} did not reach the state `{}`.", id, state.as_str()))),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Destroys the machine `id`, stopping it first if it is running.
+    pub fn delete(&self, id: &str) -> Result<(), FlyError> {
+        self.send(|| nightfly::Client::new().delete(self.url(&format!("machines/{}", id))).query(&[("force", "true")]))?;
+
+        Ok(())
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/apps/{}/{}", self.endpoint, self.app_name, path)
+    }
+
+    /// Sends the request that `request` builds, and returns its successful response.
+    ///
+    /// A `429`, or a `5xx`, is retried (building the request again), after the `Retry-After` of the response (up to the longest backoff),
+    /// or else with an exponential backoff, until the retries run out.
+    fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, FlyError> {
+        self.send_with(request, true)
+    }
+
+    /// Like [`FlyClient::send`], but a `5xx` is only retried if `retry_server_errors` is set (i.e., for idempotent requests).
+    fn send_with(&self, request: impl Fn() -> RequestBuilder, retry_server_errors: bool) -> Result<Response, FlyError> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let response = request()
+                .bearer_auth(&self.key)
+                .send()
+                .map_err(|e| if e.is_timeout() { FlyError::Timeout(e.to_string()) } else { FlyError::Transport(e.to_string()) })?;
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(Response(response));
+            }
+
+            let retry_after = retry_after(&response);
+            let body = response.text().unwrap_or_default();
+
+            let error = match status.as_u16() {
+                401 | 403 => return Err(FlyError::Auth(body)),
+                404 => return Err(FlyError::NotFound(body)),
+                429 => FlyError::RateLimited { retry_after },
+                s if s >= 500 && retry_server_errors => FlyError::Api { status: s, body },
+                s => return Err(FlyError::Api { status: s, body }),
+            };
+
+            if attempt >= self.retries {
+                return Err(error);
+            }
+
+            // A large `Retry-After` must not hold the processes that reconcile, or scale, the pool for long.
+            crate::lunatic::sleep(retry_after.unwrap_or(backoff).min(MAX_BACKOFF));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
+
+// The API token must not end up in logs.
+impl fmt::Debug for FlyClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlyClient")
+            .field("key", &"***")
+            .field("app_name", &self.app_name)
+            .field("endpoint", &self.endpoint)
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+/// A successful response of the Machines API.
+struct Response(HttpResponse);
+
+impl Response {
+    fn json<T: DeserializeOwned>(self) -> Result<T, FlyError> {
+        self.0.json::<T>().map_err(|e| FlyError::Transport(format!("Failed to parse response.  {}", e)))
+    }
+}
+
+/// Returns the amount of time that `response` asks to wait before the next request, if it sets it in seconds.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    let value = response.headers().get("retry-after")?.to_str().ok()?;
+
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
@@ -0,0 +1,128 @@
+//! The settings that provisioning takes from its environment.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{FlyClient, FlyError, ENDPOINT};
+
+/// The settings of provisioning: the API token, the endpoint of the Machines API, and the app, machine, and region of the calling
+/// (i.e., control) machine.
+///
+/// On fly.io, [`FlyConfig::from_env`] reads them from the environment of the machine, and the setters override them (e.g., to point
+/// provisioning at another endpoint).
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlyConfig {
+    key: String,
+    endpoint: String,
+    app_name: String,
+    machine_id: String,
+    region: String,
+    wait_for_nodes: bool,
+    retries: Option<u32>,
+}
+
+impl FlyConfig {
+    /// Creates the settings for the machine `machine_id`, in `region`, of `app_name`, which authenticate with the API token `key`.
+    pub fn new(key: &str, app_name: &str, machine_id: &str, region: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            endpoint: ENDPOINT.to_owned(),
+            app_name: app_name.to_owned(),
+            machine_id: machine_id.to_owned(),
+            region: region.to_owned(),
+            wait_for_nodes: true,
+            retries: None,
+        }
+    }
+
+    /// Creates the settings for the calling machine, which authenticate with the API token `key`, from the `FLY_APP_NAME`, `FLY_MACHINE_ID`,
+    /// and `FLY_REGION` environment variables that fly.io sets.
+    pub fn from_env(key: &str) -> Result<Self, FlyError> {
+        let var = |name: &str| std::env::var(name).map_err(|_| FlyError::Config(format!("The environment variable `{}` is not set.", name)));
+
+        Ok(Self::new(key, &var("FLY_APP_NAME")?, &var("FLY_MACHINE_ID")?, &var("FLY_REGION")?))
+    }
+
+    /// Sets the endpoint of the Machines API (defaults to [`ENDPOINT`]).
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the app of the machines.
+    pub fn app_name(mut self, app_name: &str) -> Self {
+        self.app_name = app_name.to_owned();
+        self
+    }
+
+    /// Sets the id of the calling machine, whose control node the worker nodes connect to.
+    pub fn machine_id(mut self, machine_id: &str) -> Self {
+        self.machine_id = machine_id.to_owned();
+        self
+    }
+
+    /// Sets the region of the machines of the specs without regions.
+    pub fn region(mut self, region: &str) -> Self {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Sets whether [`ensure_machines`](super::ensure_machines) waits for the nodes of the machines to join the cluster, once they started
+    /// (defaults to `true`).
+    pub fn wait_for_nodes(mut self, wait_for_nodes: bool) -> Self {
+        self.wait_for_nodes = wait_for_nodes;
+        self
+    }
+
+    /// Sets the number of times that a request is retried after a `429`, or a `5xx` (see [`FlyClient::retries`]).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Returns the name of the app.
+    pub fn app(&self) -> &str {
+        &self.app_name
+    }
+
+    /// Returns the id of the calling machine.
+    pub fn local_machine_id(&self) -> &str {
+        &self.machine_id
+    }
+
+    /// Returns the default region of the machines.
+    pub fn default_region(&self) -> &str {
+        &self.region
+    }
+
+    /// Returns whether [`ensure_machines`](super::ensure_machines) waits for the nodes of the machines to join the cluster.
+    pub fn waits_for_nodes(&self) -> bool {
+        self.wait_for_nodes
+    }
+
+    /// Returns a client for the machines of the app.
+    pub fn client(&self) -> FlyClient {
+        let client = FlyClient::new(&self.key, &self.app_name).endpoint(&self.endpoint);
+
+        match self.retries {
+            Some(retries) => client.retries(retries),
+            None => client,
+        }
+    }
+}
+
+// The API token must not end up in logs.
+impl fmt::Debug for FlyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlyConfig")
+            .field("key", &"***")
+            .field("endpoint", &self.endpoint)
+            .field("app_name", &self.app_name)
+            .field("machine_id", &self.machine_id)
+            .field("region", &self.region)
+            .field("wait_for_nodes", &self.wait_for_nodes)
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
@@ -0,0 +1,56 @@
+//! The error type for fly.io provisioning.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The error returned by the client, and the provisioning functions, in [`fly`](super).
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlyError {
+    /// The settings of provisioning are missing, or invalid.
+    Config(String),
+    /// The Machines API rejected the API token (i.e., with a `401`, or a `403`).
+    Auth(String),
+    /// The Machines API kept limiting the rate of requests (i.e., with a `429`), after every retry.
+    RateLimited {
+        /// The amount of time that the last response asked to wait, if any.
+        retry_after: Option<Duration>,
+    },
+    /// The machine (or app) does not exist.
+    NotFound(String),
+    /// The Machines API answered a request with another failure (including a `5xx`, after every retry).
+    Api {
+        /// The status of the response.
+        status: u16,
+        /// The body of the response.
+        body: String,
+    },
+    /// The request did not reach the Machines API, or its response could not be read.
+    Transport(String),
+    /// An operation did not complete before its timeout.
+    Timeout(String),
+    /// The machine started, but its `lunatic` node did not join the cluster before the timeout.
+    NotJoined(String),
+}
+
+impl Display for FlyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FlyError::Config(e) => write!(f, "The provisioning settings are invalid.  {}", e),
+            FlyError::Auth(e) => write!(f, "The Machines API rejected the API token.  {}", e),
+            FlyError::RateLimited { retry_after: Some(d) } => write!(f, "The Machines API limited the rate of requests (retry after {:?}).", d),
+            FlyError::RateLimited { retry_after: None } => write!(f, "The Machines API limited the rate of requests."),
+            FlyError::NotFound(e) => write!(f, "The machine was not found.  {}", e),
+            FlyError::Api { status, body } => write!(f, "The Machines API request failed with status {}.  {}", status, body),
+            FlyError::Transport(e) => write!(f, "The Machines API request could not be sent.  {}", e),
+            FlyError::Timeout(e) => write!(f, "The operation timed out.  {}", e),
+            FlyError::NotJoined(machine) => write!(f, "The node of machine `{}` did not join the cluster.", machine),
+        }
+    }
+}
+
+impl std::error::Error for FlyError {}
@@ -0,0 +1,99 @@
+//! Stopping idle worker pools, and starting them again on demand.
+
+use std::time::{Duration, Instant};
+
+use lucidity_core::{
+    cluster::Cluster,
+    dispatcher,
+    lunatic::{serializer::Bincode, Mailbox, Process},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{autoscale::STATS_TIMEOUT, ensure_machines, reconcile, stop_machines, FlyClusterSpec, FlyConfig};
+
+/// When an [`IdleMonitor`] stops the machines of its pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdlePolicy {
+    /// The amount of time without jobs on the nodes of the pool after which its machines are stopped.
+    pub idle_after: Duration,
+    /// The interval between checks of the pool.
+    pub interval: Duration,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        Self {
+            idle_after: Duration::from_secs(10 * 60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A process, on the local node, that stops the machines of a pool once it is idle, and starts them again once remote
+/// jobs arrive.
+///
+/// The pool is idle while none of its nodes runs, or queues, a job.  Remote jobs arrive as placements, on any node, that find no eligible
+/// node (see `Cluster::unplaced`), so jobs called with a timeout wait for the machines to start, while jobs without one fail with `NoEligibleNode`.
+///
+/// The machines of the pool are only stopped (see [`stop_machines`]), so the pool must be created with `auto_destroy` off, which
+/// [`FlyClusterSpec`] does.
+#[derive(Debug)]
+pub struct IdleMonitor {
+    process: Process<(), Bincode>,
+}
+
+impl IdleMonitor {
+    /// Starts monitoring the pool of `spec`, which [`ensure_machines`] provisioned.
+    pub fn start(config: &FlyConfig, spec: &FlyClusterSpec, policy: IdlePolicy) -> Self {
+        let process = Process::spawn((config.clone(), spec.clone(), policy), monitor);
+
+        Self { process }
+    }
+
+    /// Stops monitoring the pool, and leaves its machines as they are.
+    pub fn stop(self) {
+        self.process.kill();
+    }
+}
+
+fn monitor((config, spec, policy): (FlyConfig, FlyClusterSpec, IdlePolicy), _: Mailbox<(), Bincode>) {
+    let cluster = Cluster::get();
+    let mut last_active = Instant::now();
+    let mut unplaced = cluster.unplaced();
+    let mut stopped = false;
+
+    loop {
+        crate::lunatic::sleep(policy.interval);
+
+        let demand = cluster.unplaced();
+
+        if stopped {
+            // Keep the demand pending until the machines started, so that a failed start is retried.
+            if demand != unplaced && ensure_machines(&config, &spec).is_ok() {
+                stopped = false;
+                unplaced = demand;
+                last_active = Instant::now();
+            }
+
+            continue;
+        }
+
+        unplaced = demand;
+
+        if is_busy(&cluster, &spec) {
+            last_active = Instant::now();
+        } else if last_active.elapsed() >= policy.idle_after && stop_machines(&config, spec.prefix()).is_ok() {
+            stopped = true;
+        }
+    }
+}
+
+/// Returns whether any node of the pool runs, or queues, a job.
+///
+/// Nodes that do not answer (e.g., while they stop) are skipped.
+fn is_busy(cluster: &Cluster, spec: &FlyClusterSpec) -> bool {
+    reconcile::pool_nodes(cluster, spec)
+        .into_iter()
+        .filter_map(|n| dispatcher::stats_timeout(n.id, STATS_TIMEOUT))
+        .any(|stats| stats.running + stats.pending > 0)
+}
@@ -0,0 +1,107 @@
+//! The module for automatically building a `lunatic` cluster on fly.io.
+
+use std::time::Instant;
+
+use lucidity_core::lunatic::{
+    protocol::{Protocol, Send, TaskEnd},
+    Process,
+};
+
+mod autoscale;
+mod client;
+mod config;
+mod error;
+mod idle;
+mod reconcile;
+mod spec;
+
+#[cfg(test)]
+mod tests;
+
+pub use autoscale::{AutoscalePolicy, Autoscaler};
+pub use client::{FlyClient, GuestConfig, InitConfig, Machine, MachineConfig, MachineState, RestartConfig, ServiceConfig, ENDPOINT};
+pub use config::FlyConfig;
+pub use error::FlyError;
+pub use idle::{IdleMonitor, IdlePolicy};
+pub use reconcile::{MachineAction, ReconcilePlan};
+pub use spec::FlyClusterSpec;
+
+/// The label that identifies the machine of a worker node in the cluster (see `NodeInfo::labels`).
+pub const MACHINE_LABEL: &str = "fly_machine";
+
+/// Ensures that the `lunatic` cluster is running on fly.io.
+///
+/// This function takes the settings of provisioning (see [`FlyConfig::from_env`]), and the spec of the pool of machines to run.
+/// It compares the spec to the machines of the pool, keeps the machines that match it,
+/// starts the stopped ones, replaces the ones that drifted, and creates (or deletes)
+/// machines to reach the count of the spec.  The `lunatic` nodes of the machines connect
+/// to the machine id of the settings.  It returns the plan of the actions it
+/// took, once every machine of the pool started, and its node joined the cluster.
+pub fn ensure_machines(config: &FlyConfig, spec: &FlyClusterSpec) -> Result<ReconcilePlan, FlyError> {
+    let plan = plan(config, spec)?;
+
+    // Create all of the processes.
+    let mut processes = Vec::new();
+    for action in &plan.actions {
+        let machine_config = reconcile::index(spec, action.name())
+            .map(|k| spec.machine_config(k, config.app(), config.local_machine_id()))
+            .unwrap_or_default();
+        let p = Process::spawn_link(
+            (config.clone(), action.clone(), machine_config, spec.timeout()),
+            |(config, action, machine_config, timeout), m: Protocol<Send<Result<(), FlyError>, TaskEnd>>| {
+                let result = reconcile::apply(&config, &action, &machine_config, Instant::now() + timeout);
+                let _ = m.send(result);
+            },
+        );
+
+        processes.push(p);
+    }
+
+    // Wait for all of the processes to finish.
+    let mut results = Vec::new();
+    for p in processes {
+        results.push(p.result());
+    }
+
+    // Check for any errors.
+    for r in results {
+        r?;
+    }
+
+    Ok(plan)
+}
+
+/// Returns the plan of the actions that [`ensure_machines`] would take for `spec`, without taking them.
+pub fn plan(config: &FlyConfig, spec: &FlyClusterSpec) -> Result<ReconcilePlan, FlyError> {
+    let machines = list_machines(config)?;
+
+    Ok(reconcile::diff(spec, &machines, config.app(), config.local_machine_id(), config.default_region()))
+}
+
+/// Deletes every machine of the pool named `prefix` (see [`FlyClusterSpec::name_prefix`]), e.g., once a batch run finishes.
+///
+/// It returns the plan of the actions it took, once the machines are gone.
+pub fn teardown(config: &FlyConfig, prefix: &str) -> Result<ReconcilePlan, FlyError> {
+    ensure_machines(config, &FlyClusterSpec::new(0).name_prefix(prefix))
+}
+
+/// Stops the running machines of the pool named `prefix`, which keeps them (and their configuration) around, without paying for them.
+///
+/// [`ensure_machines`] starts them again.
+pub fn stop_machines(config: &FlyConfig, prefix: &str) -> Result<(), FlyError> {
+    let client = config.client();
+    let spec = FlyClusterSpec::new(0).name_prefix(prefix);
+
+    for machine in client.list()? {
+        if reconcile::index(&spec, &machine.name).is_some() && matches!(machine.state, MachineState::Started | MachineState::Starting) {
+            client.stop(&machine.id)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List the currently running machines for the app.
+pub fn list_machines(config: &FlyConfig) -> Result<Vec<Machine>, FlyError> {
+    config.client().list()
+}
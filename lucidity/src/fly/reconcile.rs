@@ -0,0 +1,249 @@
+//! The reconciliation of a pool of worker machines with its spec.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lucidity_core::cluster::{Cluster, NodeId, NodeInfo};
+use serde::{Deserialize, Serialize};
+
+use super::{FlyClient, FlyClusterSpec, FlyConfig, FlyError, Machine, MachineConfig, MachineState, MACHINE_LABEL};
+
+/// The interval between checks of a machine, or of the cluster, while waiting for a machine to become ready.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An action that reconciliation takes on one machine of a pool.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MachineAction {
+    /// The machine matches the spec, and is running (or starting).
+    Keep {
+        /// The name of the machine.
+        name: String,
+        /// The id of the machine.
+        id: String,
+    },
+    /// The machine matches the spec, but is stopped, so it is started.
+    Start {
+        /// The name of the machine.
+        name: String,
+        /// The id of the machine.
+        id: String,
+    },
+    /// The machine is missing, so it is created.
+    Create {
+        /// The name of the machine.
+        name: String,
+        /// The region of the machine.
+        region: String,
+    },
+    /// The machine drifted from the spec (or is being destroyed), so it is destroyed, and created again.
+    Replace {
+        /// The name of the machine.
+        name: String,
+        /// The id of the existing machine.
+        id: String,
+        /// The region of the new machine.
+        region: String,
+    },
+    /// The machine is beyond the count of the spec, so it is destroyed.
+    Delete {
+        /// The name of the machine.
+        name: String,
+        /// The id of the machine.
+        id: String,
+    },
+}
+
+impl MachineAction {
+    /// Returns the name of the machine of the action.
+    pub fn name(&self) -> &str {
+        match self {
+            MachineAction::Keep { name, .. } | MachineAction::Start { name, .. } | MachineAction::Create { name, .. } | MachineAction::Replace { name, .. } | MachineAction::Delete { name, .. } => {
+                name
+            }
+        }
+    }
+}
+
+/// The actions that reconciliation takes on a pool, one per machine, in the order of the machines in the pool.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReconcilePlan {
+    /// The actions.
+    pub actions: Vec<MachineAction>,
+}
+
+impl ReconcilePlan {
+    /// Returns whether the plan leaves every machine as it is.
+    pub fn is_noop(&self) -> bool {
+        self.actions.iter().all(|a| matches!(a, MachineAction::Keep { .. }))
+    }
+}
+
+/// Returns the actions that bring the machines of the app (as listed in `machines`) in line with `spec`.
+pub(crate) fn diff(spec: &FlyClusterSpec, machines: &[Machine], app_name: &str, local_machine_id: &str, default_region: &str) -> ReconcilePlan {
+    let pool = machines.iter().filter_map(|m| index(spec, &m.name).map(|k| (k, m))).collect::<HashMap<_, _>>();
+    let mut actions = Vec::new();
+
+    for k in 1..=spec.machine_count() {
+        let name = spec.machine_name(k);
+        let region = spec.machine_region(k, default_region).to_owned();
+
+        let Some(machine) = pool.get(&k) else {
+            actions.push(MachineAction::Create { name, region });
+            continue;
+        };
+
+        let id = machine.id.clone();
+        let config = spec.machine_config(k, app_name, local_machine_id);
+
+        if machine.region != region || !matches(&config, &machine.config) {
+            actions.push(MachineAction::Replace { name, id, region });
+            continue;
+        }
+
+        actions.push(match machine.state {
+            MachineState::Started | MachineState::Starting | MachineState::Replacing => MachineAction::Keep { name, id },
+            MachineState::Created | MachineState::Stopping | MachineState::Stopped => MachineAction::Start { name, id },
+            _ => MachineAction::Replace { name, id, region },
+        });
+    }
+
+    let mut extra = pool.iter().filter(|(k, _)| **k > spec.machine_count()).collect::<Vec<_>>();
+    extra.sort_by_key(|(k, _)| **k);
+
+    for (_, machine) in extra {
+        if !matches!(machine.state, MachineState::Destroying | MachineState::Destroyed) {
+            actions.push(MachineAction::Delete {
+                name: machine.name.clone(),
+                id: machine.id.clone(),
+            });
+        }
+    }
+
+    ReconcilePlan { actions }
+}
+
+/// Returns `k`, if `name` is the name of the `k`-th machine of the pool.
+pub(crate) fn index(spec: &FlyClusterSpec, name: &str) -> Option<usize> {
+    let k = name.strip_prefix(spec.prefix())?.strip_prefix('-')?.parse::<usize>().ok()?;
+
+    (k > 0).then_some(k)
+}
+
+/// Returns the nodes of the pool of `spec` that joined the cluster.
+pub(crate) fn pool_nodes(cluster: &Cluster, spec: &FlyClusterSpec) -> Vec<NodeInfo> {
+    cluster.nodes().into_iter().filter(|n| n.label(MACHINE_LABEL).and_then(|m| index(spec, m)).is_some()).collect()
+}
+
+/// Returns whether the configuration of a machine matches the desired configuration.
+///
+/// Only the parts that the spec sets are compared, since the platform adds its own defaults (e.g., metadata).
+fn matches(desired: &MachineConfig, actual: &MachineConfig) -> bool {
+    let env = desired.env.iter().all(|(k, v)| actual.env.get(k) == Some(v));
+    let restart = desired.restart.as_ref().map(|r| &r.policy) == actual.restart.as_ref().map(|r| &r.policy);
+
+    let mut desired_services = desired.services.iter().map(|s| (&s.protocol, s.internal_port)).collect::<Vec<_>>();
+    let mut actual_services = actual.services.iter().map(|s| (&s.protocol, s.internal_port)).collect::<Vec<_>>();
+    desired_services.sort();
+    actual_services.sort();
+
+    desired.image == actual.image && desired.init == actual.init && desired.guest == actual.guest && env && restart && desired_services == actual_services
+}
+
+/// Takes `action`, and waits until its machine is ready (or gone), until `deadline` passes.
+///
+/// Unless `settings` skip it, the machine is only ready once its node joined the cluster.
+pub(crate) fn apply(settings: &FlyConfig, action: &MachineAction, config: &MachineConfig, deadline: Instant) -> Result<(), FlyError> {
+    let client = &settings.client();
+
+    match action {
+        MachineAction::Keep { .. } => {}
+        MachineAction::Start { id, .. } => {
+            client.start(id)?;
+            wait_for_state(client, id, MachineState::Started, deadline)?;
+        }
+        MachineAction::Create { name, region } => {
+            create(client, name, region, config, deadline)?;
+        }
+        MachineAction::Replace { name, id, region } => {
+            destroy(client, id, deadline)?;
+            create(client, name, region, config, deadline)?;
+        }
+        MachineAction::Delete { id, .. } => {
+            return destroy(client, id, deadline);
+        }
+    }
+
+    if settings.waits_for_nodes() {
+        wait_for_node(action.name(), deadline)?;
+    }
+
+    Ok(())
+}
+
+fn create(client: &FlyClient, name: &str, region: &str, config: &MachineConfig, deadline: Instant) -> Result<(), FlyError> {
+    let machine = client.create(name, region, config)?;
+
+    wait_for_state(client, &machine.id, MachineState::Started, deadline)
+}
+
+/// Destroys the machine `id`, and waits until it is gone, so that its name is free again.
+fn destroy(client: &FlyClient, id: &str, deadline: Instant) -> Result<(), FlyError> {
+    match client.delete(id) {
+        Ok(()) => {}
+        // The machine is already gone.
+        Err(FlyError::NotFound(_)) => return Ok(()),
+        // The machine may already be on its way out.
+        Err(e @ FlyError::Api { .. }) => match client.get(id) {
+            Ok(machine) if matches!(machine.state, MachineState::Destroying | MachineState::Destroyed) => {}
+            Err(FlyError::NotFound(_)) => return Ok(()),
+            _ => return Err(e),
+        },
+        Err(e) => return Err(e),
+    }
+
+    wait_for_state(client, id, MachineState::Destroyed, deadline)
+}
+
+/// Waits until the machine `id` reaches `state`, using the `wait` endpoint, and falling back to the state of the machine.
+fn wait_for_state(client: &FlyClient, id: &str, state: MachineState, deadline: Instant) -> Result<(), FlyError> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(FlyError::Timeout(format!("The machine {} did not reach the state `{}`.", id, state.as_str())));
+        }
+
+        // The `wait` endpoint times out once its own timeout passes, and fails if the machine is already gone.
+        match client.wait(id, state, remaining) {
+            Ok(()) => return Ok(()),
+            Err(FlyError::Timeout(_) | FlyError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        match client.get(id) {
+            Ok(machine) if machine.state == state => return Ok(()),
+            Ok(_) => crate::lunatic::sleep(POLL_INTERVAL),
+            Err(FlyError::NotFound(_)) if state == MachineState::Destroyed => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Waits until the node of the machine `machine_name` joins the cluster, and returns its id.
+fn wait_for_node(machine_name: &str, deadline: Instant) -> Result<NodeId, FlyError> {
+    let cluster = Cluster::get();
+
+    loop {
+        if let Some(node) = cluster.nodes().into_iter().find(|n| n.label(MACHINE_LABEL) == Some(machine_name)) {
+            return Ok(node.id);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(FlyError::NotJoined(machine_name.to_owned()));
+        }
+
+        crate::lunatic::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,217 @@
+//! The specification of a pool of worker machines.
+
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{GuestConfig, InitConfig, MachineConfig, RestartConfig, ServiceConfig, MACHINE_LABEL};
+
+/// The port on which the `lunatic` node of a worker listens for the other nodes.
+const NODE_PORT: u16 = 3031;
+
+/// The port on which the `lunatic` control node of the calling machine listens.
+const CONTROL_PORT: u16 = 3030;
+
+/// The specification of a pool of worker machines, which [`ensure_machines`](super::ensure_machines) provisions.
+///
+/// The machines of a pool are named `{name_prefix}-{k}`, for `k` in `1..=count`, so several pools (e.g., of different sizes) can run in
+/// the same app, as long as their prefixes differ.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlyClusterSpec {
+    count: usize,
+    image: String,
+    cpu_kind: String,
+    cpus: u32,
+    memory_mb: u32,
+    env: HashMap<String, String>,
+    name_prefix: String,
+    restart_policy: String,
+    regions: Vec<String>,
+    ports: Vec<ServiceConfig>,
+    ready_timeout: Duration,
+}
+
+impl Default for FlyClusterSpec {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            image: "twitchax/lunatic:2024.01.10".to_owned(),
+            cpu_kind: "shared".to_owned(),
+            cpus: 1,
+            memory_mb: 1024,
+            env: HashMap::new(),
+            name_prefix: "lucid".to_owned(),
+            restart_policy: "always".to_owned(),
+            regions: Vec::new(),
+            ports: Vec::new(),
+            ready_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl FlyClusterSpec {
+    /// Creates the specification of a pool of `count` machines, with the default size (1 shared CPU, and 1024 MB).
+    pub fn new(count: usize) -> Self {
+        Self { count, ..Default::default() }
+    }
+
+    /// Sets the number of machines.
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Sets the image of the machines, which must run a `lunatic` node (see the `Production Setup` in the readme).
+    pub fn image(mut self, image: &str) -> Self {
+        self.image = image.to_owned();
+        self
+    }
+
+    /// Sets the kind of CPU of the machines (i.e., `shared`, or `performance`).
+    pub fn cpu_kind(mut self, cpu_kind: &str) -> Self {
+        self.cpu_kind = cpu_kind.to_owned();
+        self
+    }
+
+    /// Sets the number of CPUs of each machine.
+    pub fn cpus(mut self, cpus: u32) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Sets the amount of memory of each machine, in MB.
+    pub fn memory_mb(mut self, memory_mb: u32) -> Self {
+        self.memory_mb = memory_mb;
+        self
+    }
+
+    /// Adds an environment variable to the machines.
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Sets the prefix of the names of the machines (defaults to `lucid`).
+    pub fn name_prefix(mut self, name_prefix: &str) -> Self {
+        self.name_prefix = name_prefix.to_owned();
+        self
+    }
+
+    /// Sets the restart policy of the machines (i.e., `no`, `always`, or `on-failure`).
+    pub fn restart_policy(mut self, restart_policy: &str) -> Self {
+        self.restart_policy = restart_policy.to_owned();
+        self
+    }
+
+    /// Sets the regions of the machines, which are spread over them in turn (defaults to the region of the calling machine).
+    pub fn regions<S: AsRef<str>>(mut self, regions: impl IntoIterator<Item = S>) -> Self {
+        self.regions = regions.into_iter().map(|r| r.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// Sets the number of machines in each region, and the count of the pool to their sum.
+    ///
+    /// The regions are interleaved over the machines (e.g., `ord`, `ams`, `ord`, `ord` for 3 in `ord`, and 1 in `ams`), so that a smaller
+    /// count (e.g., from an [`Autoscaler`](super::Autoscaler)) stays spread over the regions, and a larger count repeats the same pattern.
+    pub fn region_counts<S: AsRef<str>>(mut self, counts: impl IntoIterator<Item = (S, usize)>) -> Self {
+        let mut remaining = counts.into_iter().map(|(r, n)| (r.as_ref().to_owned(), n)).collect::<Vec<_>>();
+
+        self.regions.clear();
+        while remaining.iter().any(|(_, n)| *n > 0) {
+            for (region, n) in remaining.iter_mut().filter(|(_, n)| *n > 0) {
+                self.regions.push(region.clone());
+                *n -= 1;
+            }
+        }
+
+        self.count = self.regions.len();
+        self
+    }
+
+    /// Exposes another port of the machines, next to the port of the `lunatic` node.
+    pub fn port(mut self, protocol: &str, internal_port: u16) -> Self {
+        self.ports.push(ServiceConfig {
+            protocol: protocol.to_owned(),
+            internal_port,
+        });
+        self
+    }
+
+    /// Sets the amount of time that each machine has to start, and to join the cluster (defaults to two minutes).
+    pub fn ready_timeout(mut self, ready_timeout: Duration) -> Self {
+        self.ready_timeout = ready_timeout;
+        self
+    }
+
+    /// Returns the number of machines.
+    pub fn machine_count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the prefix of the names of the machines.
+    pub fn prefix(&self) -> &str {
+        &self.name_prefix
+    }
+
+    /// Returns the amount of time that each machine has to start, and to join the cluster.
+    pub fn timeout(&self) -> Duration {
+        self.ready_timeout
+    }
+
+    /// Returns the name of the `k`-th machine (starting at 1).
+    pub fn machine_name(&self, k: usize) -> String {
+        format!("{}-{}", self.name_prefix, k)
+    }
+
+    /// Returns the region of the `k`-th machine (starting at 1), or `default` if the spec has no regions.
+    pub fn machine_region<'a>(&'a self, k: usize, default: &'a str) -> &'a str {
+        if self.regions.is_empty() {
+            return default;
+        }
+
+        &self.regions[k.saturating_sub(1) % self.regions.len()]
+    }
+
+    /// Returns the configuration of the `k`-th machine (starting at 1), whose `lunatic` node connects to the control node of `local_machine_id`
+    /// in `app_name`.
+    ///
+    /// The node carries the label [`MACHINE_LABEL`](super::MACHINE_LABEL), which identifies its machine in the cluster, and the region of
+    /// its machine (see `NodeInfo::region`).
+    pub fn machine_config(&self, k: usize, app_name: &str, local_machine_id: &str) -> MachineConfig {
+        let mut services = vec![ServiceConfig {
+            protocol: "udp".to_owned(),
+            internal_port: NODE_PORT,
+        }];
+        services.extend(self.ports.iter().cloned());
+
+        let mut env = self.env.clone();
+        if !self.regions.is_empty() {
+            env.insert("LUCIDITY_REGION".to_owned(), self.machine_region(k, "").to_owned());
+        }
+
+        let label = format!("{}={}", MACHINE_LABEL, self.machine_name(k));
+        let labels = match env.get("LUCIDITY_LABELS") {
+            Some(labels) => format!("{},{}", labels, label),
+            None => label,
+        };
+        env.insert("LUCIDITY_LABELS".to_owned(), labels);
+
+        MachineConfig {
+            image: self.image.clone(),
+            env,
+            init: InitConfig {
+                exec: Some(vec!["/app.entrypoint.sh".to_owned(), format!("http://{}.vm.{}.internal:{}/", local_machine_id, app_name, CONTROL_PORT)]),
+            },
+            guest: GuestConfig {
+                cpu_kind: self.cpu_kind.clone(),
+                cpus: self.cpus,
+                memory_mb: self.memory_mb,
+            },
+            restart: Some(RestartConfig { policy: self.restart_policy.clone() }),
+            services,
+            // Stopped machines are kept around, to be started again (see `stop_machines`), and pools are deleted explicitly.
+            auto_destroy: false,
+            ..Default::default()
+        }
+    }
+}
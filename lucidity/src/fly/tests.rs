@@ -0,0 +1,482 @@
+//! Tests of provisioning against a mock of the Machines API.
+//!
+//! These tests run in `lunatic` (see the readme), since the client talks to the mock over `lunatic` sockets.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    time::Duration,
+};
+
+use lucidity_core::channel::{channel, Sender};
+use serde::{Deserialize, Serialize};
+
+use super::*;
+use crate::lunatic::{
+    self,
+    net::{TcpListener, TcpStream},
+    serializer::Bincode,
+    Mailbox,
+};
+
+const APP: &str = "lucid-test";
+const CONTROL: &str = "control";
+const REGION: &str = "ord";
+
+/// A mock of the Machines API, which serves the machines of one app, and applies every change at once.
+struct MockApi {
+    machines: Vec<Machine>,
+    created: usize,
+    failures: Vec<Failure>,
+}
+
+/// A failure that the mock answers the next request of `method` with, instead of serving it.
+#[derive(Clone, Serialize, Deserialize)]
+struct Failure {
+    method: String,
+    status: u16,
+    retry_after: Option<u64>,
+}
+
+impl Failure {
+    fn new(method: &str, status: u16, retry_after: Option<u64>) -> Self {
+        Self {
+            method: method.to_owned(),
+            status,
+            retry_after,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateMachine {
+    name: String,
+    region: String,
+    config: MachineConfig,
+}
+
+impl MockApi {
+    /// Starts serving `machines`, and returns the settings that point provisioning at the mock.
+    fn start(machines: Vec<Machine>) -> FlyConfig {
+        MockApi::start_failing(machines, Vec::new())
+    }
+
+    /// Starts serving `machines`, answering the first requests with `failures`.
+    fn start_failing(machines: Vec<Machine>, failures: Vec<Failure>) -> FlyConfig {
+        let (port, bound) = channel::<u16>();
+        Process::spawn_link((port, machines, failures), MockApi::serve);
+
+        let endpoint = format!("http://127.0.0.1:{}/v1", bound.recv().unwrap());
+
+        FlyConfig::new("test-key", APP, CONTROL, REGION).endpoint(&endpoint).wait_for_nodes(false)
+    }
+
+    fn serve((port, machines, failures): (Sender<u16>, Vec<Machine>, Vec<Failure>), _: Mailbox<(), Bincode>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        port.send(listener.local_addr().unwrap().port()).unwrap();
+
+        let mut api = MockApi { machines, created: 0, failures };
+        while let Ok((stream, _)) = listener.accept() {
+            api.handle(stream);
+        }
+    }
+
+    /// Answers the one request of `stream`.
+    fn handle(&mut self, mut stream: TcpStream) {
+        let mut reader = BufReader::new(stream.clone());
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut request = line.split_whitespace();
+        let method = request.next().unwrap_or_default().to_owned();
+        let target = request.next().unwrap_or_default().to_owned();
+
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query = query.split('&').filter_map(|p| p.split_once('=')).collect::<HashMap<_, _>>();
+
+        let mut headers = String::new();
+        let (status, response) = match self.failures.iter().position(|f| f.method == method) {
+            Some(k) => {
+                let failure = self.failures.remove(k);
+                if let Some(seconds) = failure.retry_after {
+                    headers = format!("Retry-After: {}\r\n", seconds);
+                }
+
+                (failure.status, "\"failure\"".to_owned())
+            }
+            None => self.route(&method, path, &query, &body),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            headers,
+            response
+        )
+        .unwrap();
+        stream.flush().unwrap();
+    }
+
+    fn route(&mut self, method: &str, path: &str, query: &HashMap<&str, &str>, body: &[u8]) -> (u16, String) {
+        let Some(path) = path.strip_prefix(&format!("/v1/apps/{}/machines", APP)) else {
+            return not_found();
+        };
+        let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+
+        match (method, segments.as_slice()) {
+            ("GET", []) => ok(&self.machines),
+            ("POST", []) => {
+                let request = serde_json::from_slice::<CreateMachine>(body).unwrap();
+                self.created += 1;
+
+                let machine = Machine {
+                    id: format!("machine-{}", self.created),
+                    name: request.name,
+                    state: MachineState::Started,
+                    region: request.region,
+                    instance_id: format!("instance-{}", self.created),
+                    private_ip: String::new(),
+                    config: request.config,
+                };
+                self.machines.push(machine.clone());
+
+                ok(&machine)
+            }
+            ("GET", [id]) => match self.machine(id) {
+                Some(machine) => ok(&machine.clone()),
+                None => not_found(),
+            },
+            ("GET", [id, "wait"]) => {
+                let state = query.get("state").copied().unwrap_or("started");
+                let reached = match self.machine(id) {
+                    Some(machine) => machine.state.as_str() == state,
+                    None => state == "destroyed",
+                };
+
+                if reached {
+                    ok(&"ok")
+                } else {
+                    (408, "\"timeout\"".to_owned())
+                }
+            }
+            ("POST", [id, action @ ("start" | "stop")]) => match self.machine(id) {
+                Some(machine) => {
+                    machine.state = if *action == "start" { MachineState::Started } else { MachineState::Stopped };
+                    ok(&"ok")
+                }
+                None => not_found(),
+            },
+            ("DELETE", [id]) => match self.machines.iter().position(|m| m.id == *id) {
+                Some(k) => {
+                    self.machines.remove(k);
+                    ok(&"ok")
+                }
+                None => not_found(),
+            },
+            _ => not_found(),
+        }
+    }
+
+    fn machine(&mut self, id: &str) -> Option<&mut Machine> {
+        self.machines.iter_mut().find(|m| m.id == id)
+    }
+}
+
+fn ok<T: Serialize>(body: &T) -> (u16, String) {
+    (200, serde_json::to_string(body).unwrap())
+}
+
+fn not_found() -> (u16, String) {
+    (404, "\"not found\"".to_owned())
+}
+
+/// Returns the `k`-th machine of `spec`, as [`ensure_machines`] would create it.
+fn machine(spec: &FlyClusterSpec, k: usize, state: MachineState) -> Machine {
+    Machine {
+        id: format!("existing-{}", k),
+        name: spec.machine_name(k),
+        state,
+        region: REGION.to_owned(),
+        instance_id: format!("existing-instance-{}", k),
+        private_ip: String::new(),
+        config: spec.machine_config(k, APP, CONTROL),
+    }
+}
+
+fn names(config: &FlyConfig) -> Vec<String> {
+    let mut names = list_machines(config).unwrap().into_iter().map(|m| m.name).collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[lunatic::test]
+fn list_machines_returns_the_machines_of_the_app() {
+    let spec = FlyClusterSpec::new(2);
+    let machines = vec![machine(&spec, 1, MachineState::Started), machine(&spec, 2, MachineState::Stopped)];
+
+    let config = MockApi::start(machines.clone());
+
+    assert_eq!(list_machines(&config).unwrap(), machines);
+}
+
+#[lunatic::test]
+fn ensure_machines_creates_the_missing_machines() {
+    let spec = FlyClusterSpec::new(3).image("example/worker:1");
+    let config = MockApi::start(Vec::new());
+
+    let plan = ensure_machines(&config, &spec).unwrap();
+
+    assert_eq!(
+        plan.actions,
+        (1..=3)
+            .map(|k| MachineAction::Create {
+                name: spec.machine_name(k),
+                region: REGION.to_owned()
+            })
+            .collect::<Vec<_>>()
+    );
+
+    let machines = list_machines(&config).unwrap();
+    assert_eq!(machines.len(), 3);
+    for (k, machine) in (1..=3).zip(&machines) {
+        assert_eq!(machine.name, spec.machine_name(k));
+        assert_eq!(machine.state, MachineState::Started);
+        assert_eq!(machine.region, REGION);
+        assert_eq!(machine.config, spec.machine_config(k, APP, CONTROL));
+    }
+}
+
+#[lunatic::test]
+fn ensure_machines_keeps_and_starts_matching_machines() {
+    let spec = FlyClusterSpec::new(2);
+    let config = MockApi::start(vec![machine(&spec, 1, MachineState::Started), machine(&spec, 2, MachineState::Stopped)]);
+
+    let plan = ensure_machines(&config, &spec).unwrap();
+
+    assert_eq!(
+        plan.actions,
+        vec![
+            MachineAction::Keep {
+                name: spec.machine_name(1),
+                id: "existing-1".to_owned()
+            },
+            MachineAction::Start {
+                name: spec.machine_name(2),
+                id: "existing-2".to_owned()
+            },
+        ]
+    );
+    assert!(list_machines(&config).unwrap().iter().all(|m| m.state == MachineState::Started));
+
+    // A second run has nothing left to do.
+    assert!(ensure_machines(&config, &spec).unwrap().is_noop());
+}
+
+#[lunatic::test]
+fn ensure_machines_replaces_drifted_machines() {
+    let old = FlyClusterSpec::new(1).image("example/worker:1");
+    let new = old.clone().image("example/worker:2");
+    let config = MockApi::start(vec![machine(&old, 1, MachineState::Started)]);
+
+    let plan = ensure_machines(&config, &new).unwrap();
+
+    assert_eq!(
+        plan.actions,
+        vec![MachineAction::Replace {
+            name: new.machine_name(1),
+            id: "existing-1".to_owned(),
+            region: REGION.to_owned()
+        }]
+    );
+
+    let machines = list_machines(&config).unwrap();
+    assert_eq!(machines.len(), 1);
+    assert_ne!(machines[0].id, "existing-1");
+    assert_eq!(machines[0].config.image, "example/worker:2");
+}
+
+#[lunatic::test]
+fn ensure_machines_deletes_the_machines_beyond_the_count() {
+    let spec = FlyClusterSpec::new(3);
+    let other = FlyClusterSpec::new(1).name_prefix("other");
+    let config = MockApi::start(vec![
+        machine(&spec, 1, MachineState::Started),
+        machine(&spec, 2, MachineState::Started),
+        machine(&spec, 3, MachineState::Stopped),
+        machine(&other, 1, MachineState::Started),
+    ]);
+
+    let plan = ensure_machines(&config, &spec.clone().count(1)).unwrap();
+
+    assert_eq!(
+        plan.actions,
+        vec![
+            MachineAction::Keep {
+                name: spec.machine_name(1),
+                id: "existing-1".to_owned()
+            },
+            MachineAction::Delete {
+                name: spec.machine_name(2),
+                id: "existing-2".to_owned()
+            },
+            MachineAction::Delete {
+                name: spec.machine_name(3),
+                id: "existing-3".to_owned()
+            },
+        ]
+    );
+    assert_eq!(names(&config), vec![spec.machine_name(1), other.machine_name(1)]);
+}
+
+#[lunatic::test]
+fn teardown_deletes_every_machine_of_the_pool() {
+    let spec = FlyClusterSpec::new(2);
+    let other = FlyClusterSpec::new(1).name_prefix("other");
+    let config = MockApi::start(vec![
+        machine(&spec, 1, MachineState::Started),
+        machine(&spec, 2, MachineState::Stopped),
+        machine(&other, 1, MachineState::Started),
+    ]);
+
+    let plan = teardown(&config, spec.prefix()).unwrap();
+
+    assert_eq!(plan.actions.len(), 2);
+    assert!(plan.actions.iter().all(|a| matches!(a, MachineAction::Delete { .. })));
+    assert_eq!(names(&config), vec![other.machine_name(1)]);
+}
+
+#[lunatic::test]
+fn delete_fails_for_a_missing_machine() {
+    let spec = FlyClusterSpec::new(1);
+    let config = MockApi::start(vec![machine(&spec, 1, MachineState::Started)]);
+    let client = config.client();
+
+    client.delete("existing-1").unwrap();
+
+    assert!(matches!(client.delete("existing-1"), Err(FlyError::NotFound(_))));
+    assert!(matches!(client.get("existing-1"), Err(FlyError::NotFound(_))));
+    assert!(list_machines(&config).unwrap().is_empty());
+}
+
+#[lunatic::test]
+fn stop_machines_stops_the_running_machines_of_the_pool() {
+    let spec = FlyClusterSpec::new(2);
+    let other = FlyClusterSpec::new(1).name_prefix("other");
+    let config = MockApi::start(vec![
+        machine(&spec, 1, MachineState::Started),
+        machine(&spec, 2, MachineState::Started),
+        machine(&other, 1, MachineState::Started),
+    ]);
+
+    stop_machines(&config, spec.prefix()).unwrap();
+
+    let states = list_machines(&config).unwrap().into_iter().map(|m| (m.name, m.state)).collect::<HashMap<_, _>>();
+    assert_eq!(states[&spec.machine_name(1)], MachineState::Stopped);
+    assert_eq!(states[&spec.machine_name(2)], MachineState::Stopped);
+    assert_eq!(states[&other.machine_name(1)], MachineState::Started);
+}
+
+#[lunatic::test]
+fn requests_are_retried_after_a_429_or_a_5xx() {
+    let spec = FlyClusterSpec::new(1);
+    let config = MockApi::start_failing(
+        vec![machine(&spec, 1, MachineState::Started)],
+        vec![Failure::new("GET", 429, Some(0)), Failure::new("GET", 503, Some(0))],
+    );
+
+    assert_eq!(list_machines(&config).unwrap().len(), 1);
+}
+
+#[lunatic::test]
+fn requests_fail_once_the_retries_run_out() {
+    let failures = (0..3).map(|_| Failure::new("GET", 500, Some(0))).chain((0..3).map(|_| Failure::new("POST", 429, Some(0)))).collect();
+    let config = MockApi::start_failing(Vec::new(), failures).retries(2);
+    let client = config.client();
+
+    assert_eq!(
+        client.list(),
+        Err(FlyError::Api {
+            status: 500,
+            body: "\"failure\"".to_owned()
+        })
+    );
+    assert_eq!(client.start("missing"), Err(FlyError::RateLimited { retry_after: Some(Duration::ZERO) }));
+    assert!(client.list().unwrap().is_empty());
+}
+
+#[lunatic::test]
+fn creates_are_only_retried_after_a_429() {
+    let spec = FlyClusterSpec::new(1);
+    let config = MockApi::start_failing(Vec::new(), vec![Failure::new("POST", 502, Some(0)), Failure::new("POST", 429, Some(0))]);
+    let client = config.client();
+    let machine_config = spec.machine_config(1, APP, CONTROL);
+
+    assert_eq!(
+        client.create(&spec.machine_name(1), REGION, &machine_config),
+        Err(FlyError::Api {
+            status: 502,
+            body: "\"failure\"".to_owned()
+        })
+    );
+    assert!(client.create(&spec.machine_name(1), REGION, &machine_config).is_ok());
+    assert_eq!(names(&config), vec![spec.machine_name(1)]);
+}
+
+#[lunatic::test]
+fn auth_and_missing_machines_fail_without_retries() {
+    let config = MockApi::start_failing(Vec::new(), vec![Failure::new("GET", 401, None)]);
+    let client = config.client();
+
+    assert!(matches!(client.list(), Err(FlyError::Auth(_))));
+    assert!(matches!(client.get("missing"), Err(FlyError::NotFound(_))));
+    assert!(matches!(client.delete("missing"), Err(FlyError::NotFound(_))));
+}
+
+#[lunatic::test]
+fn ensure_machines_propagates_the_failures_of_its_actions() {
+    let spec = FlyClusterSpec::new(2);
+    let config = MockApi::start_failing(vec![machine(&spec, 1, MachineState::Started)], vec![Failure::new("POST", 403, None)]);
+
+    assert!(matches!(ensure_machines(&config, &spec), Err(FlyError::Auth(_))));
+    assert_eq!(names(&config), vec![spec.machine_name(1)]);
+
+    // Once the failure passes, the next run creates the missing machine.
+    let plan = ensure_machines(&config, &spec).unwrap();
+
+    assert_eq!(
+        plan.actions[1],
+        MachineAction::Create {
+            name: spec.machine_name(2),
+            region: REGION.to_owned()
+        }
+    );
+    assert_eq!(names(&config), vec![spec.machine_name(1), spec.machine_name(2)]);
+}
+
+#[lunatic::test]
+fn clients_do_not_print_the_api_token() {
+    let config = FlyConfig::new("secret-key", APP, CONTROL, REGION);
+
+    assert!(!format!("{:?}", config).contains("secret-key"));
+    assert!(!format!("{:?}", config.client()).contains("secret-key"));
+}
@@ -1,9 +1,11 @@
-//! The `lucidity` crate.  Re-exports the core components and provides a macro for creating jobs.
+//! The `lucidity` crate.  Re-exports the core components and provides the macros for creating jobs, and actors.
 //#![doc = include_str!("../../README.md")]
 #![warn(rustdoc::broken_intra_doc_links, rust_2018_idioms, clippy::all, missing_docs)]
 
+pub use lucidity_core::channel::channel;
 pub use lucidity_core::Job;
-pub use lucidity_macros::job;
+pub use lucidity_core::{CallOptions, Fanout, JobError, Restart, RestartNode, Speculation};
+pub use lucidity_macros::{actor, job};
 
 pub use lucidity_core::lunatic;
 pub use lucidity_core::rand;
@@ -11,5 +13,17 @@ pub use lucidity_core::serde;
 
 pub use lucidity_core::lunatic::abstract_process;
 
+pub use lucidity_core::actor;
+pub use lucidity_core::call;
+pub use lucidity_core::channel;
+pub use lucidity_core::cluster;
+pub use lucidity_core::dispatcher;
+pub use lucidity_core::limits;
+pub use lucidity_core::pubsub;
+pub use lucidity_core::registry;
+pub use lucidity_core::sharding;
+pub use lucidity_core::supervision;
+pub use lucidity_core::sync;
+
 #[cfg(feature = "fly")]
 pub mod fly;